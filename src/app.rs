@@ -1,4 +1,10 @@
-use crate::{config::ToProperties, prelude::*, webhook::mutate};
+use crate::{
+    config::{AnnotationConfig, PatchConfig, Rule, ToProperties},
+    prelude::*,
+    selector::NamespaceFilter,
+    webhook::{mutate, validate},
+    workload::CustomResource,
+};
 
 // handy alias
 type DynHandler = BoxEndpoint<'static, Response>;
@@ -12,46 +18,37 @@ struct RouteDef {
 #[derive(Clone)]
 pub struct AppState {
     pub log: Arc<Logger>,
-    pub container_properties: Container,
+    pub rules: Arc<Vec<Rule>>,
+    pub annotations: Arc<AnnotationConfig>,
+    pub namespaces: Arc<NamespaceFilter>,
+    pub custom_resources: Arc<Vec<CustomResource>>,
+    pub patch: Arc<PatchConfig>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct Container {
-    pub name: String,
-    pub port_name: String,
-    pub port_number: u16,
-}
-
-impl Container {
-    pub fn new(name: &str, port_name: &str, port_number: u16) -> Self {
-        Container {
-            name: name.to_string(),
-            port_name: port_name.to_string(),
-            port_number,
-        }
-    }
-}
-
-impl ToProperties<Container> for Config {
-    type Output = Container;
+impl ToProperties<Rule> for Config {
+    type Output = Vec<Rule>;
 
     fn to_properties(config: &Config) -> Self::Output {
-        Container::new(
-            &config.container_patch.name,
-            &config.container_patch.port_name,
-            config.container_patch.port_number,
-        )
+        config.rules.clone()
     }
 }
 
 impl AppState {
     pub fn build(config: &Config) -> Self {
         let log = Arc::new(Logger::build(&config.log_output));
-        let container_properties = Config::to_properties(config);
+        let rules = Arc::new(Config::to_properties(config));
+        let annotations = Arc::new(config.annotations.clone());
+        let namespaces = Arc::new(config.namespaces.clone());
+        let custom_resources = Arc::new(config.custom_resources.clone());
+        let patch = Arc::new(config.patch.clone());
 
         AppState {
             log,
-            container_properties,
+            rules,
+            annotations,
+            namespaces,
+            custom_resources,
+            patch,
         }
     }
 }
@@ -69,6 +66,10 @@ pub async fn builder(config: &Config) -> AddDataEndpoint<Route, AppState> {
             path: "/mutate",
             handler: post(mutate).boxed(),
         },
+        RouteDef {
+            path: "/validate",
+            handler: post(validate).boxed(),
+        },
     ];
 
     let api = routes
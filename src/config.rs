@@ -1,4 +1,15 @@
+use crate::jsonpatch::Pointer;
+use crate::overrides::is_valid_port_name;
+use crate::selector::{
+    ContainerList, ContainerSelector, NamespaceFilter, Pattern, validate_label_selector,
+};
+use crate::workload::CustomResource;
+use k8s_openapi::api::core::v1::{Container, EnvVarSource, Volume, VolumeMount};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use serde::de::DeserializeOwned;
 use serde_yaml::Value;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
 
@@ -61,7 +72,11 @@ pub struct Config {
     pub port: u16,
     pub addr: String,
     pub log_output: String,
-    pub container_patch: ContainerPatch,
+    pub rules: Vec<Rule>,
+    pub annotations: AnnotationConfig,
+    pub namespaces: NamespaceFilter,
+    pub custom_resources: Vec<CustomResource>,
+    pub patch: PatchConfig,
     pub cert_path: String,
     pub key_path: String,
 }
@@ -91,6 +106,140 @@ pub fn load_certificate(config: &Config) -> std::io::Result<ServerCertificate> {
     Ok(ServerCertificate { cert, key })
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InjectionMode {
+    /// Only pods annotated with one of the enabled values are mutated.
+    #[default]
+    OptIn,
+    /// Every pod is mutated unless annotated with one of the disabled values.
+    OptOut,
+}
+
+impl InjectionMode {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "opt-in" => Ok(InjectionMode::OptIn),
+            "opt-out" => Ok(InjectionMode::OptOut),
+            _ => Err(format!("unknown injection mode {}", value)),
+        }
+    }
+}
+
+/// Pod annotations read and written by the webhook. All keys share one
+/// domain prefix, e.g. `syscallx86.com/container-port-injector`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnnotationConfig {
+    pub prefix: String,
+    pub key: String,
+    pub enabled_values: Vec<String>,
+    pub disabled_values: Vec<String>,
+    pub mode: InjectionMode,
+}
+
+impl Default for AnnotationConfig {
+    fn default() -> Self {
+        AnnotationConfig {
+            prefix: "syscallx86.com".to_string(),
+            key: "container-port-injector".to_string(),
+            enabled_values: vec!["true".to_string()],
+            disabled_values: vec!["false".to_string()],
+            mode: InjectionMode::default(),
+        }
+    }
+}
+
+impl AnnotationConfig {
+    /// Full annotation key for `name` under the configured prefix.
+    pub fn key(&self, name: &str) -> String {
+        format!("{}/{}", self.prefix, name)
+    }
+
+    pub fn injector_key(&self) -> String {
+        self.key(&self.key)
+    }
+
+    pub fn is_enabled(&self, value: &str) -> bool {
+        self.enabled_values
+            .iter()
+            .any(|v| v.eq_ignore_ascii_case(value.trim()))
+    }
+
+    pub fn is_disabled(&self, value: &str) -> bool {
+        self.disabled_values
+            .iter()
+            .any(|v| v.eq_ignore_ascii_case(value.trim()))
+    }
+
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.trim_end_matches('/').to_string();
+        self
+    }
+    pub fn with_key(mut self, key: &str) -> Self {
+        self.key = key.to_string();
+        self
+    }
+    pub fn with_enabled_values(mut self, values: Vec<String>) -> Self {
+        self.enabled_values = values;
+        self
+    }
+    pub fn with_disabled_values(mut self, values: Vec<String>) -> Self {
+        self.disabled_values = values;
+        self
+    }
+    pub fn with_mode(mut self, mode: InjectionMode) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+/// What to do when the patch fails the in-process verification.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PatchFailurePolicy {
+    /// Admit the object unchanged and warn about it.
+    #[default]
+    Allow,
+    /// Deny the admission request.
+    Deny,
+}
+
+impl PatchFailurePolicy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "allow" => Ok(PatchFailurePolicy::Allow),
+            "deny" => Ok(PatchFailurePolicy::Deny),
+            _ => Err(format!("unknown patch failure policy {}", value)),
+        }
+    }
+}
+
+/// Checks of the JSON Patch before it is sent to the API server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PatchConfig {
+    /// Largest encoded patch in bytes.
+    pub max_size: usize,
+    pub failure_policy: PatchFailurePolicy,
+}
+
+impl Default for PatchConfig {
+    fn default() -> Self {
+        PatchConfig {
+            max_size: 256 * 1024,
+            failure_policy: PatchFailurePolicy::default(),
+        }
+    }
+}
+
+impl PatchConfig {
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+    pub fn with_failure_policy(mut self, policy: PatchFailurePolicy) -> Self {
+        self.failure_policy = policy;
+        self
+    }
+}
+
 pub trait ToProperties<T> {
     type Output;
 
@@ -129,6 +278,496 @@ impl ContainerPatch {
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+    Sctp,
+}
+
+impl Protocol {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_ascii_uppercase().as_str() {
+            "TCP" => Ok(Protocol::Tcp),
+            "UDP" => Ok(Protocol::Udp),
+            "SCTP" => Ok(Protocol::Sctp),
+            _ => Err(format!("unknown protocol {}", value)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+            Protocol::Sctp => "SCTP",
+        }
+    }
+}
+
+/// What to do when an injected port number collides with a port of another
+/// container. All containers of a pod share one network namespace.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CollisionPolicy {
+    /// Leave the port out and keep going.
+    #[default]
+    Skip,
+    /// Deny the admission request.
+    Fail,
+    /// Use the next free port number above the configured one.
+    Next,
+}
+
+impl CollisionPolicy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "skip" => Ok(CollisionPolicy::Skip),
+            "fail" => Ok(CollisionPolicy::Fail),
+            "next" => Ok(CollisionPolicy::Next),
+            _ => Err(format!("unknown collision policy {}", value)),
+        }
+    }
+}
+
+/// What to do when the port name is already used with another number.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ConflictPolicy {
+    /// Leave the port out and keep going.
+    #[default]
+    Skip,
+    /// Overwrite the existing port of the target container.
+    Replace,
+    /// Inject the port as `<name>-2`, `<name>-3`, ...
+    Rename,
+    /// Deny the admission request.
+    Fail,
+}
+
+impl ConflictPolicy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "skip" => Ok(ConflictPolicy::Skip),
+            "replace" => Ok(ConflictPolicy::Replace),
+            "rename" => Ok(ConflictPolicy::Rename),
+            "fail" => Ok(ConflictPolicy::Fail),
+            _ => Err(format!("unknown conflict policy {}", value)),
+        }
+    }
+}
+
+/// Where the target container says which port its process binds.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PortSource {
+    /// Plain value of an env var of the container, e.g. `METRICS_PORT`.
+    Env(String),
+    /// Pod annotation, e.g. `prometheus.io/port`.
+    Annotation(String),
+    /// Command-line flag of the container, as `--metrics-addr=:9102` or
+    /// `--metrics-addr :9102`.
+    Arg(String),
+}
+
+impl fmt::Display for PortSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortSource::Env(name) => write!(f, "env {}", name),
+            PortSource::Annotation(key) => write!(f, "annotation {}", key),
+            PortSource::Arg(flag) => write!(f, "argument {}", flag),
+        }
+    }
+}
+
+/// Port injected into the matched container.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortPatch {
+    pub name: String,
+    pub number: u16,
+    /// When set, `number` is ignored and the lowest port of the range not
+    /// used anywhere in the pod is allocated instead.
+    pub range: Option<(u16, u16)>,
+    /// When set, `number` is read from the container and the port is only
+    /// injected when the source is there.
+    pub source: Option<PortSource>,
+    pub protocol: Protocol,
+    pub host_port: Option<u16>,
+    pub host_ip: Option<String>,
+}
+
+impl PortPatch {
+    pub fn new(name: &str, number: u16) -> Self {
+        PortPatch {
+            name: name.to_string(),
+            number,
+            range: None,
+            source: None,
+            protocol: Protocol::default(),
+            host_port: None,
+            host_ip: None,
+        }
+    }
+    pub fn with_range(mut self, start: u16, end: u16) -> Self {
+        self.range = Some((start, end));
+        self
+    }
+    pub fn with_source(mut self, source: PortSource) -> Self {
+        self.source = Some(source);
+        self
+    }
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+    pub fn with_host_port(mut self, host_port: u16) -> Self {
+        self.host_port = Some(host_port);
+        self
+    }
+    pub fn with_host_ip(mut self, host_ip: &str) -> Self {
+        self.host_ip = Some(host_ip.to_string());
+        self
+    }
+}
+
+/// Where the value of an injected environment variable comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EnvSource {
+    Value(String),
+    /// Kubernetes `EnvVarSource`: `fieldRef`, `resourceFieldRef`,
+    /// `secretKeyRef` or `configMapKeyRef`.
+    ValueFrom(serde_json::Value),
+    /// Number of the named port of the container, e.g. the port injected by
+    /// the same rule.
+    Port(String),
+}
+
+/// What to do when the container already has the variable.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum EnvMerge {
+    /// Leave the existing value alone.
+    #[default]
+    Keep,
+    /// Replace the existing value.
+    Override,
+    /// Add the value to the end of the existing one, e.g. `JAVA_OPTS`.
+    Append,
+}
+
+impl EnvMerge {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "keep" => Ok(EnvMerge::Keep),
+            "override" => Ok(EnvMerge::Override),
+            "append" => Ok(EnvMerge::Append),
+            _ => Err(format!("unknown env merge {}", value)),
+        }
+    }
+}
+
+/// Environment variable injected into the matched container.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnvPatch {
+    pub name: String,
+    pub source: EnvSource,
+    pub merge: EnvMerge,
+    /// Put between the existing and the appended value.
+    pub separator: String,
+}
+
+impl EnvPatch {
+    pub fn new(name: &str, source: EnvSource) -> Self {
+        EnvPatch {
+            name: name.to_string(),
+            source,
+            merge: EnvMerge::default(),
+            separator: " ".to_string(),
+        }
+    }
+    pub fn value(name: &str, value: &str) -> Self {
+        Self::new(name, EnvSource::Value(value.to_string()))
+    }
+    pub fn value_from(name: &str, source: serde_json::Value) -> Self {
+        Self::new(name, EnvSource::ValueFrom(source))
+    }
+    pub fn port(name: &str, port_name: &str) -> Self {
+        Self::new(name, EnvSource::Port(port_name.to_string()))
+    }
+    pub fn with_merge(mut self, merge: EnvMerge) -> Self {
+        self.merge = merge;
+        self
+    }
+    pub fn with_separator(mut self, separator: &str) -> Self {
+        self.separator = separator.to_string();
+        self
+    }
+}
+
+/// Discovery annotations of a monitoring system.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MonitoringPreset {
+    /// `prometheus.io/scrape`, `prometheus.io/port` and `prometheus.io/path`.
+    Prometheus,
+    /// `ad.datadoghq.com/<container>.checks` with an OpenMetrics check.
+    Datadog,
+}
+
+impl MonitoringPreset {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "prometheus" => Ok(MonitoringPreset::Prometheus),
+            "datadog" => Ok(MonitoringPreset::Datadog),
+            _ => Err(format!("unknown monitoring preset {}", value)),
+        }
+    }
+}
+
+/// Pod annotations pointing a scraper at a port injected by the rule.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Monitoring {
+    pub preset: MonitoringPreset,
+    /// Port of the rule, the first one when not set.
+    pub port: Option<String>,
+    pub path: String,
+    /// Replace annotations the pod already has.
+    pub overwrite: bool,
+}
+
+impl Monitoring {
+    pub fn new(preset: MonitoringPreset) -> Self {
+        Monitoring {
+            preset,
+            port: None,
+            path: "/metrics".to_string(),
+            overwrite: false,
+        }
+    }
+    pub fn with_port(mut self, port: &str) -> Self {
+        self.port = Some(port.to_string());
+        self
+    }
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = path.to_string();
+        self
+    }
+    pub fn with_overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+}
+
+/// Built-in sidecar settings of a service mesh.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MeshPreset {
+    Consul,
+    Istio,
+}
+
+impl MeshPreset {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "consul" => Ok(MeshPreset::Consul),
+            "istio" => Ok(MeshPreset::Istio),
+            _ => Err(format!("unknown mesh preset {}", value)),
+        }
+    }
+
+    /// Sidecar containers of the mesh. consul-k8s 1.0 replaced the
+    /// `envoy-sidecar` container with `consul-dataplane`.
+    pub fn selector(&self) -> ContainerSelector {
+        let (names, images): (&[&str], &[&str]) = match self {
+            MeshPreset::Consul => (
+                &["consul-dataplane", "envoy-sidecar"],
+                &["*consul-dataplane*", "*envoy*"],
+            ),
+            MeshPreset::Istio => (&["istio-proxy"], &["*proxyv2*"]),
+        };
+
+        let selector = names.iter().fold(ContainerSelector::default(), |s, n| {
+            s.with_name(Pattern::parse(n).expect("preset pattern"))
+        });
+        images.iter().fold(selector, |s, i| {
+            s.with_image(Pattern::parse(i).expect("preset pattern"))
+        })
+    }
+
+    /// Envoy admin and Prometheus ports of the sidecar.
+    pub fn ports(&self) -> Vec<PortPatch> {
+        match self {
+            MeshPreset::Consul => vec![
+                PortPatch::new("envoy-admin", 19000),
+                PortPatch::new("envoy-metrics", 20200),
+            ],
+            MeshPreset::Istio => vec![
+                PortPatch::new("envoy-admin", 15000),
+                PortPatch::new("http-envoy-prom", 15090),
+            ],
+        }
+    }
+
+    /// Pod annotation listing inbound ports the mesh doesn't redirect to
+    /// the sidecar.
+    pub fn exclude_annotation(&self) -> &'static str {
+        match self {
+            MeshPreset::Consul => "consul.hashicorp.com/transparent-proxy-exclude-inbound-ports",
+            MeshPreset::Istio => "traffic.sidecar.istio.io/excludeInboundPorts",
+        }
+    }
+}
+
+/// Container added to the pod by a rule, e.g. a node-exporter sidecar or a
+/// log shipper, together with the volumes it needs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContainerInjection {
+    /// Container spec copied into the pod as it is.
+    pub template: serde_json::Value,
+    /// `containers`, `initContainers` or `sidecars`.
+    pub list: ContainerList,
+    /// Pod volumes added unless the pod has a volume of the same name.
+    pub volumes: Vec<serde_json::Value>,
+    /// Volume mounts added to the containers matched by the rule, e.g. the
+    /// log directory shared with the shipper.
+    pub mounts: Vec<serde_json::Value>,
+}
+
+impl ContainerInjection {
+    pub fn new(template: serde_json::Value) -> Self {
+        ContainerInjection {
+            template,
+            list: ContainerList::Containers,
+            volumes: Vec::new(),
+            mounts: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.template["name"].as_str().unwrap_or_default()
+    }
+
+    pub fn with_list(mut self, list: ContainerList) -> Self {
+        self.list = list;
+        self
+    }
+    pub fn with_volume(mut self, volume: serde_json::Value) -> Self {
+        self.volumes.push(volume);
+        self
+    }
+    pub fn with_mount(mut self, mount: serde_json::Value) -> Self {
+        self.mounts.push(mount);
+        self
+    }
+}
+
+/// One port-injection rule. All rules are evaluated for every pod and their
+/// results are merged into a single JSON Patch.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    pub container: ContainerSelector,
+    /// Container lists searched for matching containers.
+    pub targets: Vec<ContainerList>,
+    pub ports: Vec<PortPatch>,
+    /// Environment variables set after the ports are injected.
+    pub env: Vec<EnvPatch>,
+    /// Monitoring annotations written for the injected port.
+    pub monitoring: Vec<Monitoring>,
+    /// Pod annotation the injected port numbers are added to, e.g. the
+    /// inbound port exclusion of a service mesh.
+    pub exclude_annotation: Option<String>,
+    /// Containers added to the pod before the ports are injected.
+    pub inject: Vec<ContainerInjection>,
+    pub on_collision: CollisionPolicy,
+    pub on_conflict: ConflictPolicy,
+    /// Pod label selector (`matchLabels` and `matchExpressions`).
+    pub selector: LabelSelector,
+}
+
+impl Rule {
+    pub fn new(name: &str) -> Self {
+        Rule {
+            name: name.to_string(),
+            container: ContainerSelector::default(),
+            targets: vec![ContainerList::Containers],
+            ports: Vec::new(),
+            env: Vec::new(),
+            monitoring: Vec::new(),
+            exclude_annotation: None,
+            inject: Vec::new(),
+            on_collision: CollisionPolicy::default(),
+            on_conflict: ConflictPolicy::default(),
+            selector: LabelSelector::default(),
+        }
+    }
+    pub fn with_container(mut self, container: ContainerSelector) -> Self {
+        self.container = container;
+        self
+    }
+    pub fn with_targets(mut self, targets: Vec<ContainerList>) -> Self {
+        self.targets = targets;
+        self
+    }
+    pub fn with_port(mut self, port: PortPatch) -> Self {
+        self.ports.push(port);
+        self
+    }
+    pub fn with_env(mut self, env: EnvPatch) -> Self {
+        self.env.push(env);
+        self
+    }
+    pub fn with_monitoring(mut self, monitoring: Monitoring) -> Self {
+        self.monitoring.push(monitoring);
+        self
+    }
+    pub fn with_exclude_annotation(mut self, key: &str) -> Self {
+        self.exclude_annotation = Some(key.to_string());
+        self
+    }
+    /// Fills in the container, ports and exclusion annotation the rule
+    /// doesn't configure itself. Mesh sidecars may be native sidecars, so
+    /// those are searched too; targets set afterwards replace that.
+    pub fn with_preset(mut self, preset: MeshPreset) -> Self {
+        if self.container.is_empty() {
+            self.container = preset.selector();
+        }
+        self.targets = vec![ContainerList::Containers, ContainerList::NativeSidecars];
+        if self.ports.is_empty() {
+            self.ports = preset.ports();
+        }
+        if self.exclude_annotation.is_none() {
+            self.exclude_annotation = Some(preset.exclude_annotation().to_string());
+        }
+        self
+    }
+    pub fn with_injection(mut self, injection: ContainerInjection) -> Self {
+        self.inject.push(injection);
+        self
+    }
+    pub fn with_on_collision(mut self, policy: CollisionPolicy) -> Self {
+        self.on_collision = policy;
+        self
+    }
+    pub fn with_on_conflict(mut self, policy: ConflictPolicy) -> Self {
+        self.on_conflict = policy;
+        self
+    }
+    pub fn with_selector(mut self, key: &str, value: &str) -> Self {
+        self.selector
+            .match_labels
+            .get_or_insert_with(BTreeMap::new)
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+    pub fn with_label_selector(mut self, selector: LabelSelector) -> Self {
+        self.selector = selector;
+        self
+    }
+}
+
+impl From<ContainerPatch> for Rule {
+    fn from(cp: ContainerPatch) -> Self {
+        Rule::new("container_patch")
+            .with_container(ContainerSelector::default().with_name(Pattern::Exact(cp.name)))
+            .with_port(PortPatch::new(&cp.port_name, cp.port_number))
+    }
+}
+
 impl Config {
     pub fn new() -> Self {
         Config::default()
@@ -157,17 +796,35 @@ impl Config {
         self
     }
 
+    /// Replaces all rules with the single legacy `container_patch` rule.
     pub fn with_container_patch(mut self, props: ContainerPatch) -> Self {
-        self.container_patch = props;
+        self.rules = vec![props.into()];
         self
     }
 
-    pub fn get_container_properties(&self) -> ContainerPatch {
-        ContainerPatch {
-            name: self.container_patch.name.clone(),
-            port_name: self.container_patch.port_name.clone(),
-            port_number: self.container_patch.port_number,
-        }
+    pub fn with_rules(mut self, rules: Vec<Rule>) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    pub fn with_annotations(mut self, annotations: AnnotationConfig) -> Self {
+        self.annotations = annotations;
+        self
+    }
+
+    pub fn with_namespaces(mut self, namespaces: NamespaceFilter) -> Self {
+        self.namespaces = namespaces;
+        self
+    }
+
+    pub fn with_custom_resource(mut self, resource: CustomResource) -> Self {
+        self.custom_resources.push(resource);
+        self
+    }
+
+    pub fn with_patch(mut self, patch: PatchConfig) -> Self {
+        self.patch = patch;
+        self
     }
 }
 
@@ -177,7 +834,11 @@ impl Default for Config {
             port: 8443,
             addr: String::from("0.0.0.0"),
             log_output: String::from("console"),
-            container_patch: ContainerPatch::default(),
+            rules: vec![ContainerPatch::default().into()],
+            annotations: AnnotationConfig::default(),
+            namespaces: NamespaceFilter::default(),
+            custom_resources: Vec::new(),
+            patch: PatchConfig::default(),
             cert_path: CERT.to_string(),
             key_path: KEY.to_string(),
         }
@@ -227,6 +888,26 @@ impl ConfigLoader for FileConfigLoader {
                             let cp_config = get_cp_config(v);
                             config = config.with_container_patch(cp_config);
                         }
+                        Value::String(s) if s == "annotations" => {
+                            config = config.with_annotations(get_annotations_config(v));
+                        }
+                        Value::String(s) if s == "namespaces" => {
+                            config = config.with_namespaces(get_namespaces_config(v));
+                        }
+                        Value::String(s) if s == "patch" => {
+                            config = config.with_patch(get_patch_config(v));
+                        }
+                        _ => continue,
+                    },
+                    Value::Sequence(_) => match k {
+                        Value::String(s) if s == "rules" => {
+                            config = config.with_rules(get_rules_config(v));
+                        }
+                        Value::String(s) if s == "custom_resources" => {
+                            for resource in get_custom_resources_config(v) {
+                                config = config.with_custom_resource(resource);
+                            }
+                        }
                         _ => continue,
                     },
                     _ => continue,
@@ -238,6 +919,565 @@ impl ConfigLoader for FileConfigLoader {
     }
 }
 
+fn get_annotations_config(v: Value) -> AnnotationConfig {
+    let mut annotations = AnnotationConfig::default();
+
+    if let Value::Mapping(a_map) = v {
+        for (a_k, a_v) in a_map {
+            match (a_k.as_str(), a_v) {
+                (Some("prefix"), Value::String(s)) => annotations = annotations.with_prefix(&s),
+                (Some("key"), Value::String(s)) => annotations = annotations.with_key(&s),
+                (Some("values"), values_v) => {
+                    annotations = annotations.with_enabled_values(get_strings(values_v));
+                }
+                (Some("disabled_values"), values_v) => {
+                    annotations = annotations.with_disabled_values(get_strings(values_v));
+                }
+                (Some("mode"), Value::String(s)) => {
+                    let mode = InjectionMode::parse(&s).unwrap_or_else(|e| panic!("{}", e));
+                    annotations = annotations.with_mode(mode);
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    annotations
+}
+
+fn get_patch_config(v: Value) -> PatchConfig {
+    let mut patch = PatchConfig::default();
+
+    if let Value::Mapping(p_map) = v {
+        for (p_k, p_v) in p_map {
+            match (p_k.as_str(), p_v) {
+                (Some("max_size"), Value::Number(n)) => {
+                    let max_size = n
+                        .as_u64()
+                        .filter(|n| *n > 0)
+                        .unwrap_or_else(|| panic!("patch: invalid max_size {}", n));
+                    patch = patch.with_max_size(max_size as usize);
+                }
+                (Some("failure_policy"), Value::String(s)) => {
+                    let policy =
+                        PatchFailurePolicy::parse(&s).unwrap_or_else(|e| panic!("patch: {}", e));
+                    patch = patch.with_failure_policy(policy);
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    patch
+}
+
+/// Accepts either a single string or a list of strings.
+fn get_strings(v: Value) -> Vec<String> {
+    match v {
+        Value::String(s) => vec![s],
+        Value::Sequence(seq) => seq
+            .into_iter()
+            .filter_map(|p| p.as_str().map(str::to_string))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn get_namespaces_config(v: Value) -> NamespaceFilter {
+    let mut namespaces = NamespaceFilter::default();
+
+    if let Value::Mapping(ns_map) = v {
+        for (ns_k, ns_v) in ns_map {
+            let patterns = get_patterns(ns_v, "namespaces");
+            match ns_k.as_str() {
+                Some("allow") => namespaces.allow.extend(patterns),
+                Some("deny") => namespaces.deny.extend(patterns),
+                _ => continue,
+            }
+        }
+    }
+
+    namespaces
+}
+
+fn get_custom_resources_config(v: Value) -> Vec<CustomResource> {
+    match v {
+        Value::Sequence(seq) => seq
+            .into_iter()
+            .enumerate()
+            .map(|(idx, cr_v)| get_custom_resource_config(cr_v, idx))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn get_custom_resource_config(v: Value, idx: usize) -> CustomResource {
+    let mut resource = CustomResource::default();
+    let mut pointers = 0;
+
+    if let Value::Mapping(cr_map) = v {
+        for (cr_k, cr_v) in cr_map {
+            match (cr_k.as_str(), cr_v) {
+                (Some("group"), Value::String(s)) => resource.group = s,
+                (Some("version"), Value::String(s)) => resource = resource.with_version(&s),
+                (Some("kind"), Value::String(s)) => resource.kind = s,
+                (Some("pod_spec"), Value::String(s)) => {
+                    resource.pointer = s;
+                    resource.template = false;
+                    pointers += 1;
+                }
+                (Some("pod_template"), Value::String(s)) => {
+                    resource.pointer = s;
+                    resource.template = true;
+                    pointers += 1;
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    if resource.kind.is_empty() {
+        panic!("custom resource {}: kind is required", idx);
+    }
+    if pointers != 1 {
+        panic!(
+            "custom resource {}: exactly one of pod_spec and pod_template is required",
+            resource.kind
+        );
+    }
+    if !resource.pointer.starts_with('/') || Pointer::parse(&resource.pointer).is_err() {
+        panic!(
+            "custom resource {}: {} is not a JSON pointer",
+            resource.kind, resource.pointer
+        );
+    }
+
+    resource
+}
+
+/// Accepts a Kubernetes label selector, or a plain label map used as
+/// `matchLabels`.
+fn get_label_selector_config(v: Value, rule_name: &str) -> LabelSelector {
+    let is_selector = v
+        .as_mapping()
+        .is_some_and(|m| m.contains_key("matchLabels") || m.contains_key("matchExpressions"));
+
+    let selector = if is_selector {
+        serde_yaml::from_value(v).unwrap_or_else(|e| panic!("rule {}: {}", rule_name, e))
+    } else {
+        let labels: BTreeMap<String, String> =
+            serde_yaml::from_value(v).unwrap_or_else(|e| panic!("rule {}: {}", rule_name, e));
+        LabelSelector {
+            match_labels: Some(labels),
+            ..Default::default()
+        }
+    };
+
+    validate_label_selector(&selector).unwrap_or_else(|e| panic!("rule {}: {}", rule_name, e));
+    selector
+}
+
+fn get_rules_config(v: Value) -> Vec<Rule> {
+    let mut rules = Vec::new();
+
+    if let Value::Sequence(seq) = v {
+        for (i, rule_v) in seq.into_iter().enumerate() {
+            rules.push(get_rule_config(rule_v, i));
+        }
+    }
+
+    rules
+}
+
+fn get_rule_config(v: Value, idx: usize) -> Rule {
+    let mut rule = Rule::new(&format!("rule-{}", idx));
+    let mut preset = None;
+    // nastaví se až po presetu, ať explicitní `targets: containers` platí
+    let mut targets = None;
+
+    if let Value::Mapping(rule_map) = v {
+        for (r_k, r_v) in rule_map {
+            match (r_k.as_str(), r_v) {
+                (Some("name"), Value::String(s)) => rule.name = s,
+                (Some("container"), Value::String(s)) => {
+                    let selector = ContainerSelector::name(&s)
+                        .unwrap_or_else(|e| panic!("rule {}: {}", rule.name, e));
+                    rule = rule.with_container(selector);
+                }
+                (Some("container"), container_v @ Value::Mapping(_)) => {
+                    let selector = get_container_selector_config(container_v, &rule.name);
+                    rule = rule.with_container(selector);
+                }
+                (Some("targets"), targets_v) => {
+                    targets = Some(get_targets_config(targets_v, &rule.name));
+                }
+                (Some("ports"), Value::Sequence(seq)) => {
+                    for port_v in seq {
+                        let port = get_port_config(port_v, &rule.name);
+                        rule = rule.with_port(port);
+                    }
+                }
+                (Some("env"), Value::Sequence(seq)) => {
+                    for env_v in seq {
+                        let env = get_env_config(env_v, &rule.name);
+                        rule = rule.with_env(env);
+                    }
+                }
+                (Some("preset"), Value::String(s)) => {
+                    let p = MeshPreset::parse(&s)
+                        .unwrap_or_else(|e| panic!("rule {}: {}", rule.name, e));
+                    preset = Some(p);
+                }
+                (Some("exclude_annotation"), Value::String(s)) => {
+                    rule = rule.with_exclude_annotation(&s);
+                }
+                (Some("monitoring"), Value::Sequence(seq)) => {
+                    for monitoring_v in seq {
+                        let monitoring = get_monitoring_config(monitoring_v, &rule.name);
+                        rule = rule.with_monitoring(monitoring);
+                    }
+                }
+                (Some("inject"), Value::Sequence(seq)) => {
+                    for inject_v in seq {
+                        let injection = get_injection_config(inject_v, &rule.name);
+                        rule = rule.with_injection(injection);
+                    }
+                }
+                (Some("on_collision"), Value::String(s)) => {
+                    let policy = CollisionPolicy::parse(&s)
+                        .unwrap_or_else(|e| panic!("rule {}: {}", rule.name, e));
+                    rule = rule.with_on_collision(policy);
+                }
+                (Some("on_conflict"), Value::String(s)) => {
+                    let policy = ConflictPolicy::parse(&s)
+                        .unwrap_or_else(|e| panic!("rule {}: {}", rule.name, e));
+                    rule = rule.with_on_conflict(policy);
+                }
+                (Some("selector"), selector_v @ Value::Mapping(_)) => {
+                    let selector = get_label_selector_config(selector_v, &rule.name);
+                    rule = rule.with_label_selector(selector);
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    // preset doplní jen to, co pravidlo nenastavuje samo
+    if let Some(preset) = preset {
+        rule = rule.with_preset(preset);
+    }
+    if let Some(targets) = targets {
+        rule = rule.with_targets(targets);
+    }
+
+    // pravidlo jen s injekcí kontejneru nepotřebuje cílový kontejner
+    let patches_container = !rule.ports.is_empty() || !rule.env.is_empty();
+    if rule.container.is_empty() && (rule.inject.is_empty() || patches_container) {
+        panic!("rule {} has no container", rule.name);
+    }
+    if rule.container.is_empty() && rule.inject.iter().any(|i| !i.mounts.is_empty()) {
+        panic!("rule {}: mounts need a container", rule.name);
+    }
+    if !rule.monitoring.is_empty() && rule.ports.is_empty() {
+        panic!("rule {}: monitoring needs a port", rule.name);
+    }
+    for port in rule.monitoring.iter().filter_map(|m| m.port.as_ref()) {
+        if !rule.ports.iter().any(|p| &p.name == port) {
+            panic!(
+                "rule {}: monitoring port {} is not a port of the rule",
+                rule.name, port
+            );
+        }
+    }
+
+    rule
+}
+
+/// Converts a YAML part of the config into the Kubernetes type `T`, keeping
+/// it as JSON.
+fn get_k8s_value<T: DeserializeOwned>(v: Value, what: &str, rule_name: &str) -> serde_json::Value {
+    let json = serde_json::to_value(&v)
+        .unwrap_or_else(|e| panic!("rule {}: invalid {}: {}", rule_name, what, e));
+    serde_json::from_value::<T>(json.clone())
+        .unwrap_or_else(|e| panic!("rule {}: invalid {}: {}", rule_name, what, e));
+    json
+}
+
+fn get_env_config(v: Value, rule_name: &str) -> EnvPatch {
+    let mut name = String::new();
+    let mut sources = Vec::new();
+    let mut merge = EnvMerge::default();
+    let mut separator = None;
+
+    if let Value::Mapping(env_map) = v {
+        for (e_k, e_v) in env_map {
+            match (e_k.as_str(), e_v) {
+                (Some("name"), Value::String(s)) => name = s,
+                (Some("value"), Value::String(s)) => sources.push(EnvSource::Value(s)),
+                (Some("value"), value_v @ (Value::Number(_) | Value::Bool(_))) => {
+                    // env hodnota je vždy řetězec
+                    let s = serde_json::to_value(&value_v).unwrap().to_string();
+                    sources.push(EnvSource::Value(s));
+                }
+                (Some("valueFrom"), from_v @ Value::Mapping(_)) => {
+                    let from = get_k8s_value::<EnvVarSource>(from_v, "valueFrom", rule_name);
+                    sources.push(EnvSource::ValueFrom(from));
+                }
+                (Some("port"), Value::String(s)) => sources.push(EnvSource::Port(s)),
+                (Some("merge"), Value::String(s)) => {
+                    merge =
+                        EnvMerge::parse(&s).unwrap_or_else(|e| panic!("rule {}: {}", rule_name, e));
+                }
+                (Some("separator"), Value::String(s)) => separator = Some(s),
+                _ => continue,
+            }
+        }
+    }
+
+    if name.is_empty() {
+        panic!("rule {}: env needs a name", rule_name);
+    }
+    if sources.len() != 1 {
+        panic!(
+            "rule {}: env {} needs exactly one of value, valueFrom and port",
+            rule_name, name
+        );
+    }
+
+    let mut env = EnvPatch::new(&name, sources.remove(0)).with_merge(merge);
+    if let Some(separator) = separator {
+        env = env.with_separator(&separator);
+    }
+    env
+}
+
+fn get_monitoring_config(v: Value, rule_name: &str) -> Monitoring {
+    let mut preset = None;
+    let mut port = None;
+    let mut path = None;
+    let mut overwrite = false;
+
+    if let Value::Mapping(monitoring_map) = v {
+        for (m_k, m_v) in monitoring_map {
+            match (m_k.as_str(), m_v) {
+                (Some("preset"), Value::String(s)) => {
+                    let p = MonitoringPreset::parse(&s)
+                        .unwrap_or_else(|e| panic!("rule {}: {}", rule_name, e));
+                    preset = Some(p);
+                }
+                (Some("port"), Value::String(s)) => port = Some(s),
+                (Some("path"), Value::String(s)) => path = Some(s),
+                (Some("overwrite"), Value::Bool(b)) => overwrite = b,
+                _ => continue,
+            }
+        }
+    }
+
+    let Some(preset) = preset else {
+        panic!("rule {}: monitoring needs a preset", rule_name);
+    };
+    let mut monitoring = Monitoring::new(preset).with_overwrite(overwrite);
+    if let Some(port) = port {
+        monitoring = monitoring.with_port(&port);
+    }
+    if let Some(path) = path {
+        monitoring = monitoring.with_path(&path);
+    }
+    monitoring
+}
+
+fn get_injection_config(v: Value, rule_name: &str) -> ContainerInjection {
+    let mut template = None;
+    let mut list = ContainerList::Containers;
+    let mut volumes = Vec::new();
+    let mut mounts = Vec::new();
+
+    if let Value::Mapping(i_map) = v {
+        for (i_k, i_v) in i_map {
+            match (i_k.as_str(), i_v) {
+                (Some("container"), container_v @ Value::Mapping(_)) => {
+                    template = Some(get_k8s_value::<Container>(
+                        container_v,
+                        "container",
+                        rule_name,
+                    ));
+                }
+                (Some("target"), Value::String(s)) => {
+                    list = ContainerList::parse(&s)
+                        .unwrap_or_else(|e| panic!("rule {}: {}", rule_name, e));
+                }
+                (Some("volumes"), Value::Sequence(seq)) => {
+                    volumes = seq
+                        .into_iter()
+                        .map(|v| get_k8s_value::<Volume>(v, "volume", rule_name))
+                        .collect();
+                }
+                (Some("mounts"), Value::Sequence(seq)) => {
+                    mounts = seq
+                        .into_iter()
+                        .map(|v| get_k8s_value::<VolumeMount>(v, "volume mount", rule_name))
+                        .collect();
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    let Some(template) = template else {
+        panic!("rule {}: inject needs a container", rule_name);
+    };
+    // k8s_openapi doplní chybějící name prázdným řetězcem
+    if template["name"].as_str().is_none_or(str::is_empty) {
+        panic!("rule {}: injected container needs a name", rule_name);
+    }
+    if list == ContainerList::EphemeralContainers {
+        panic!("rule {}: ephemeral containers can't be injected", rule_name);
+    }
+
+    ContainerInjection {
+        template,
+        list,
+        volumes,
+        mounts,
+    }
+}
+
+fn get_targets_config(v: Value, rule_name: &str) -> Vec<ContainerList> {
+    let values = get_strings(v);
+
+    if values.is_empty() {
+        panic!("rule {} has no targets", rule_name);
+    }
+
+    values
+        .iter()
+        .map(|t| ContainerList::parse(t).unwrap_or_else(|e| panic!("rule {}: {}", rule_name, e)))
+        .collect()
+}
+
+fn get_container_selector_config(v: Value, rule_name: &str) -> ContainerSelector {
+    let mut selector = ContainerSelector::default();
+
+    if let Value::Mapping(sel_map) = v {
+        for (s_k, s_v) in sel_map {
+            let patterns = get_patterns(s_v, &format!("rule {}", rule_name));
+            match s_k.as_str() {
+                Some("name") => selector.names.extend(patterns),
+                Some("image") => selector.images.extend(patterns),
+                Some("exclude") => selector.exclude.extend(patterns),
+                _ => continue,
+            }
+        }
+    }
+
+    selector
+}
+
+/// Accepts either a single pattern or a list of patterns; `context` (e.g.
+/// `rule envoy`) prefixes the panic message.
+fn get_patterns(v: Value, context: &str) -> Vec<Pattern> {
+    get_strings(v)
+        .iter()
+        .map(|p| Pattern::parse(p).unwrap_or_else(|e| panic!("{}: {}", context, e)))
+        .collect()
+}
+
+fn get_port_config(v: Value, rule_name: &str) -> PortPatch {
+    let mut port = PortPatch::new("", 0);
+
+    if let Value::Mapping(port_map) = v {
+        for (p_k, p_v) in port_map {
+            match (p_k.as_str(), p_v) {
+                (Some("name"), Value::String(s)) => port.name = s,
+                (Some("number"), Value::Number(n)) => port.number = get_port_number(&n, rule_name),
+                (Some("range"), Value::String(s)) => {
+                    let (start, end) = get_port_range(&s, rule_name);
+                    port = port.with_range(start, end);
+                }
+                (Some("from"), Value::Mapping(from_map)) => {
+                    port.source = Some(get_port_source(from_map, rule_name));
+                }
+                (Some("protocol"), Value::String(s)) => {
+                    let protocol =
+                        Protocol::parse(&s).unwrap_or_else(|e| panic!("rule {}: {}", rule_name, e));
+                    port = port.with_protocol(protocol);
+                }
+                (Some("app_protocol"), _) => {
+                    // ContainerPort nemá appProtocol, API server by ho zahodil
+                    panic!(
+                        "rule {}: app_protocol is only defined on Service ports, not on container ports",
+                        rule_name
+                    );
+                }
+                (Some("host_port"), Value::Number(n)) => {
+                    port = port.with_host_port(get_port_number(&n, rule_name));
+                }
+                (Some("host_ip"), Value::String(s)) => port = port.with_host_ip(&s),
+                _ => continue,
+            }
+        }
+    }
+
+    if port.name.is_empty() || (port.number == 0 && port.range.is_none() && port.source.is_none()) {
+        panic!(
+            "rule {} has a port without name, number, range or from",
+            rule_name
+        );
+    }
+    // API server chce IANA_SVC_NAME, k8s_openapi jméno nekontroluje
+    if !is_valid_port_name(&port.name) {
+        panic!("rule {}: {} is not a valid port name", rule_name, port.name);
+    }
+    if port.source.is_some() && (port.number != 0 || port.range.is_some()) {
+        panic!(
+            "rule {}: port {} takes its number from the container, drop number and range",
+            rule_name, port.name
+        );
+    }
+
+    port
+}
+
+/// Reads `from:` of a port, e.g. `{ env: METRICS_PORT }`.
+fn get_port_source(from_map: serde_yaml::Mapping, rule_name: &str) -> PortSource {
+    let mut sources = Vec::new();
+    for (f_k, f_v) in from_map {
+        match (f_k.as_str(), f_v) {
+            (Some("env"), Value::String(s)) => sources.push(PortSource::Env(s)),
+            (Some("annotation"), Value::String(s)) => sources.push(PortSource::Annotation(s)),
+            (Some("arg"), Value::String(s)) => sources.push(PortSource::Arg(s)),
+            _ => continue,
+        }
+    }
+
+    match sources.len() {
+        1 => sources.remove(0),
+        _ => panic!(
+            "rule {}: port from needs exactly one of env, annotation and arg",
+            rule_name
+        ),
+    }
+}
+
+/// Parses `9100-9199` style port ranges.
+fn get_port_range(value: &str, rule_name: &str) -> (u16, u16) {
+    let range = value
+        .split_once('-')
+        .and_then(|(start, end)| Some((start.trim().parse().ok()?, end.trim().parse().ok()?)));
+
+    match range {
+        Some((start, end)) if start > 0 && start <= end => (start, end),
+        _ => panic!("rule {}: invalid port range {}", rule_name, value),
+    }
+}
+
+fn get_port_number(n: &serde_yaml::Number, rule_name: &str) -> u16 {
+    match n.as_u64().map(u16::try_from) {
+        Some(Ok(number)) if number > 0 => number,
+        _ => panic!("rule {}: port number {} is out of range", rule_name, n),
+    }
+}
+
 fn get_cp_config(v: Value) -> ContainerPatch {
     let mut cp_config = ContainerPatch::default();
 
@@ -267,5 +1507,12 @@ fn get_cp_config(v: Value) -> ContainerPatch {
         }
     }
 
+    if !is_valid_port_name(&cp_config.port_name) {
+        panic!(
+            "container_patch: {} is not a valid port name",
+            cp_config.port_name
+        );
+    }
+
     cp_config
 }
@@ -0,0 +1,55 @@
+use crate::jsonpatch::{PatchOp, Pointer};
+use serde_json::Value;
+
+/// JSON Patch (RFC 6902) turning `old` into `new`.
+///
+/// Objects and arrays are compared member by member, so only what actually
+/// changed ends up in the patch. Elements appended to an array are added at
+/// `-`, elements dropped from its end are removed from the last one.
+pub fn diff(old: &Value, new: &Value) -> Vec<PatchOp> {
+    let mut ops = Vec::new();
+    diff_at(Pointer::root(), Some(old), Some(new), &mut ops);
+    ops
+}
+
+/// Diff of a single member of two objects, `None` when it is missing.
+pub fn diff_member(key: &str, old: &Value, new: &Value) -> Vec<PatchOp> {
+    let mut ops = Vec::new();
+    diff_at(
+        Pointer::root().key(key),
+        old.get(key),
+        new.get(key),
+        &mut ops,
+    );
+    ops
+}
+
+fn diff_at(path: Pointer, old: Option<&Value>, new: Option<&Value>, ops: &mut Vec<PatchOp>) {
+    match (old, new) {
+        (None, None) => {}
+        (Some(_), None) => ops.push(PatchOp::remove(path)),
+        (None, Some(new)) => ops.push(PatchOp::add(path, new.clone())),
+        (Some(old), Some(new)) if old == new => {}
+        (Some(Value::Object(old)), Some(Value::Object(new))) => {
+            for key in old.keys().filter(|k| !new.contains_key(*k)) {
+                ops.push(PatchOp::remove(path.clone().key(key)));
+            }
+            for (key, value) in new {
+                diff_at(path.clone().key(key), old.get(key), Some(value), ops);
+            }
+        }
+        (Some(Value::Array(old)), Some(Value::Array(new))) => {
+            for (idx, (old, new)) in old.iter().zip(new).enumerate() {
+                diff_at(path.clone().index(idx), Some(old), Some(new), ops);
+            }
+            // odebíráme od konce, ať indexy zbylých prvků platí
+            for idx in (new.len()..old.len()).rev() {
+                ops.push(PatchOp::remove(path.clone().index(idx)));
+            }
+            for value in new.iter().skip(old.len()) {
+                ops.push(PatchOp::add(path.clone().end(), value.clone()));
+            }
+        }
+        (Some(_), Some(new)) => ops.push(PatchOp::replace(path, new.clone())),
+    }
+}
@@ -0,0 +1,243 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+/// Escapes a JSON Pointer reference token (RFC 6901).
+pub fn escape_pointer(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+fn unescape_pointer(token: &str) -> String {
+    token.replace("~1", "/").replace("~0", "~")
+}
+
+/// JSON Pointer (RFC 6901), built token by token so keys such as
+/// `syscallx86.com/status` are always escaped.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Pointer(String);
+
+impl Pointer {
+    /// The whole document.
+    pub fn root() -> Self {
+        Pointer::default()
+    }
+
+    pub fn parse(pointer: &str) -> Result<Self, String> {
+        let valid = pointer.is_empty()
+            || pointer.starts_with('/')
+                && pointer
+                    .split('~')
+                    .skip(1)
+                    .all(|s| s.starts_with('0') || s.starts_with('1'));
+        match valid {
+            true => Ok(Pointer(pointer.to_string())),
+            false => Err(format!("{} is not a JSON pointer", pointer)),
+        }
+    }
+
+    /// Object member `key`.
+    pub fn key(mut self, key: &str) -> Self {
+        self.0.push('/');
+        self.0.push_str(&escape_pointer(key));
+        self
+    }
+
+    /// Array element `idx`.
+    pub fn index(self, idx: usize) -> Self {
+        self.key(&idx.to_string())
+    }
+
+    /// Past the last array element, where `add` appends.
+    pub fn end(self) -> Self {
+        self.key("-")
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Unescaped reference tokens.
+    pub fn tokens(&self) -> impl Iterator<Item = String> + '_ {
+        self.0.split('/').skip(1).map(unescape_pointer)
+    }
+
+    /// The pointer with `prefix` put in front of it.
+    pub fn under(&self, prefix: &Pointer) -> Pointer {
+        Pointer(format!("{}{}", prefix.0, self.0))
+    }
+
+    /// Rest of the pointer when it starts with all tokens of `prefix`.
+    pub fn strip_prefix(&self, prefix: &Pointer) -> Option<Pointer> {
+        let rest = self.0.strip_prefix(&prefix.0)?;
+        (rest.is_empty() || rest.starts_with('/')).then(|| Pointer(rest.to_string()))
+    }
+
+    /// Parent pointer and the unescaped last token, `None` for the root.
+    fn split_last(&self) -> Option<(Pointer, String)> {
+        let (parent, last) = self.0.rsplit_once('/')?;
+        Some((Pointer(parent.to_string()), unescape_pointer(last)))
+    }
+}
+
+impl TryFrom<String> for Pointer {
+    type Error = String;
+
+    fn try_from(pointer: String) -> Result<Self, Self::Error> {
+        Pointer::parse(&pointer)
+    }
+}
+
+impl From<Pointer> for String {
+    fn from(pointer: Pointer) -> Self {
+        pointer.0
+    }
+}
+
+impl fmt::Display for Pointer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// JSON Patch operation (RFC 6902).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOp {
+    Add { path: Pointer, value: Value },
+    Remove { path: Pointer },
+    Replace { path: Pointer, value: Value },
+    Test { path: Pointer, value: Value },
+    Move { from: Pointer, path: Pointer },
+    Copy { from: Pointer, path: Pointer },
+}
+
+impl PatchOp {
+    pub fn add(path: Pointer, value: Value) -> Self {
+        PatchOp::Add { path, value }
+    }
+
+    pub fn remove(path: Pointer) -> Self {
+        PatchOp::Remove { path }
+    }
+
+    pub fn replace(path: Pointer, value: Value) -> Self {
+        PatchOp::Replace { path, value }
+    }
+
+    /// The op with `path` (and `from`) rewritten by `f`.
+    pub fn map_pointers(self, f: impl Fn(Pointer) -> Pointer) -> Self {
+        match self {
+            PatchOp::Add { path, value } => PatchOp::Add {
+                path: f(path),
+                value,
+            },
+            PatchOp::Remove { path } => PatchOp::Remove { path: f(path) },
+            PatchOp::Replace { path, value } => PatchOp::Replace {
+                path: f(path),
+                value,
+            },
+            PatchOp::Test { path, value } => PatchOp::Test {
+                path: f(path),
+                value,
+            },
+            PatchOp::Move { from, path } => PatchOp::Move {
+                from: f(from),
+                path: f(path),
+            },
+            PatchOp::Copy { from, path } => PatchOp::Copy {
+                from: f(from),
+                path: f(path),
+            },
+        }
+    }
+}
+
+/// Applies the ops to `doc` in order. On error `doc` may be partially
+/// patched, apply to a copy when that matters.
+pub fn apply(doc: &mut Value, ops: &[PatchOp]) -> Result<(), String> {
+    for op in ops {
+        match op {
+            PatchOp::Add { path, value } => add(doc, path, value.clone())?,
+            PatchOp::Remove { path } => {
+                remove(doc, path)?;
+            }
+            PatchOp::Replace { path, value } => {
+                *get_mut(doc, path)? = value.clone();
+            }
+            PatchOp::Test { path, value } => {
+                if get_mut(doc, path)? != value {
+                    return Err(format!("test of {} failed", path));
+                }
+            }
+            PatchOp::Move { from, path } => {
+                if path
+                    .strip_prefix(from)
+                    .is_some_and(|rest| rest != Pointer::root())
+                {
+                    return Err(format!("can't move {} into itself", from));
+                }
+                let value = remove(doc, from)?;
+                add(doc, path, value)?;
+            }
+            PatchOp::Copy { from, path } => {
+                let value = get_mut(doc, from)?.clone();
+                add(doc, path, value)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn get_mut<'a>(doc: &'a mut Value, path: &Pointer) -> Result<&'a mut Value, String> {
+    doc.pointer_mut(path.as_str())
+        .ok_or_else(|| format!("{} does not exist", path))
+}
+
+/// Array index token, without leading zeros.
+fn array_index(token: &str, len: usize, path: &Pointer) -> Result<usize, String> {
+    token
+        .parse::<usize>()
+        .ok()
+        .filter(|idx| *idx <= len && idx.to_string() == token)
+        .ok_or_else(|| format!("{} is not a valid array index", path))
+}
+
+fn add(doc: &mut Value, path: &Pointer, value: Value) -> Result<(), String> {
+    let Some((parent, last)) = path.split_last() else {
+        *doc = value;
+        return Ok(());
+    };
+
+    match get_mut(doc, &parent)? {
+        Value::Object(map) => {
+            map.insert(last, value);
+        }
+        Value::Array(items) if last == "-" => items.push(value),
+        Value::Array(items) => {
+            let idx = array_index(&last, items.len(), path)?;
+            items.insert(idx, value);
+        }
+        _ => return Err(format!("{} is not an object or array", parent)),
+    }
+
+    Ok(())
+}
+
+fn remove(doc: &mut Value, path: &Pointer) -> Result<Value, String> {
+    let Some((parent, last)) = path.split_last() else {
+        return Ok(std::mem::take(doc));
+    };
+
+    let removed = match get_mut(doc, &parent)? {
+        Value::Object(map) => map.remove(&last),
+        Value::Array(items) => {
+            let idx = array_index(&last, items.len(), path)?;
+            (idx < items.len()).then(|| items.remove(idx))
+        }
+        _ => None,
+    };
+
+    removed.ok_or_else(|| format!("{} does not exist", path))
+}
@@ -1,10 +1,15 @@
 pub mod app;
 pub mod args;
 pub mod config;
+pub mod diff;
+pub mod jsonpatch;
 pub mod logging;
+pub mod overrides;
 pub mod prelude;
+pub mod selector;
 pub mod status;
 pub mod webhook;
+pub mod workload;
 
 #[cfg(test)]
 mod tests;
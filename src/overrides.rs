@@ -0,0 +1,125 @@
+use crate::{
+    config::{AnnotationConfig, ContainerPatch, PortPatch, Protocol, Rule},
+    selector::ContainerSelector,
+};
+use std::collections::BTreeMap;
+
+/// Target container of the default rule, e.g. `envoy`.
+pub const CONTAINER_ANNOTATION: &str = "container";
+/// Ports of the default rule, e.g. `metrics:9102/TCP,admin:9901`.
+pub const PORTS_ANNOTATION: &str = "ports";
+
+/// Rules to evaluate for one pod together with warnings about invalid
+/// overrides.
+pub struct Overrides {
+    pub rules: Vec<Rule>,
+    pub warnings: Vec<String>,
+}
+
+/// Applies per-pod annotation overrides to the first (default) rule. The
+/// overridden rule ignores its label selector, since the pod asked for it
+/// explicitly. Invalid values are reported and the config value is kept.
+pub fn apply_overrides(
+    rules: &[Rule],
+    annotations: Option<&BTreeMap<String, String>>,
+    config: &AnnotationConfig,
+) -> Overrides {
+    let mut rules = rules.to_vec();
+    let mut warnings = Vec::new();
+
+    let container_key = config.key(CONTAINER_ANNOTATION);
+    let ports_key = config.key(PORTS_ANNOTATION);
+    let container = annotations.and_then(|a| a.get(&container_key));
+    let ports = annotations.and_then(|a| a.get(&ports_key));
+    if container.is_none() && ports.is_none() {
+        return Overrides { rules, warnings };
+    }
+
+    if rules.is_empty() {
+        rules.push(ContainerPatch::default().into());
+    }
+    let rule = &mut rules[0];
+    rule.selector = Default::default();
+
+    if let Some(container) = container {
+        match parse_container(container) {
+            Ok(selector) => rule.container = selector,
+            Err(e) => warnings.push(format!("{}: {}", container_key, e)),
+        }
+    }
+
+    if let Some(ports) = ports {
+        match parse_ports(ports) {
+            Ok(ports) => rule.ports = ports,
+            Err(e) => warnings.push(format!("{}: {}", ports_key, e)),
+        }
+    }
+
+    Overrides { rules, warnings }
+}
+
+fn parse_container(value: &str) -> Result<ContainerSelector, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err("container name is empty".to_string());
+    }
+
+    ContainerSelector::name(value)
+}
+
+/// Parses `name:number[/protocol]` entries separated by commas.
+pub fn parse_ports(value: &str) -> Result<Vec<PortPatch>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(parse_port)
+        .collect::<Result<Vec<_>, _>>()
+        .and_then(|ports| {
+            if ports.is_empty() {
+                Err("no ports given".to_string())
+            } else {
+                Ok(ports)
+            }
+        })
+}
+
+fn parse_port(entry: &str) -> Result<PortPatch, String> {
+    let (name, rest) = entry
+        .split_once(':')
+        .ok_or_else(|| format!("{} is not in name:number[/protocol] format", entry))?;
+    let (number, protocol) = match rest.split_once('/') {
+        Some((number, protocol)) => (number, Some(protocol)),
+        None => (rest, None),
+    };
+
+    if !is_valid_port_name(name) {
+        return Err(format!("{} is not a valid port name", name));
+    }
+
+    let number = match number.parse::<u16>() {
+        Ok(n) if n > 0 => n,
+        _ => return Err(format!("{} is not a valid port number", number)),
+    };
+
+    let mut port = PortPatch::new(name, number);
+    if let Some(protocol) = protocol {
+        port = port.with_protocol(Protocol::parse(protocol)?);
+    }
+
+    Ok(port)
+}
+
+/// IANA_SVC_NAME: at most 15 lowercase alphanumerics or hyphens, at least
+/// one letter, no leading, trailing or adjacent hyphens.
+pub fn is_valid_port_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 15
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && name.chars().any(|c| c.is_ascii_lowercase())
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && !name.contains("--")
+}
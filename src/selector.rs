@@ -0,0 +1,259 @@
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, LabelSelectorRequirement};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// String matcher used by container selectors.
+///
+/// `re:<regex>` is a regular expression, a value containing `*`, `?` or `[`
+/// is a glob and anything else must match exactly. Both regex and glob have
+/// to match the whole value.
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    Exact(String),
+    Glob(glob::Pattern),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if let Some(re) = value.strip_prefix("re:") {
+            return Regex::new(&format!("^(?:{})$", re))
+                .map(Pattern::Regex)
+                .map_err(|e| format!("invalid regex {}: {}", re, e));
+        }
+
+        if value.contains(['*', '?', '[']) {
+            return glob::Pattern::new(value)
+                .map(Pattern::Glob)
+                .map_err(|e| format!("invalid glob {}: {}", value, e));
+        }
+
+        Ok(Pattern::Exact(value.to_string()))
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            Pattern::Exact(s) => s == value,
+            Pattern::Glob(g) => g.matches(value),
+            Pattern::Regex(r) => r.is_match(value),
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::Exact(s) => write!(f, "{}", s),
+            Pattern::Glob(g) => write!(f, "{}", g.as_str()),
+            Pattern::Regex(r) => {
+                let re = r.as_str();
+                write!(f, "re:{}", &re[4..re.len() - 2])
+            }
+        }
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_string() == other.to_string()
+    }
+}
+
+/// Selects the containers a rule is applied to.
+///
+/// A container matches when its name matches one of `names` and its image
+/// matches one of `images` (an empty list matches everything), and no
+/// `exclude` pattern matches either its name or its image.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContainerSelector {
+    pub names: Vec<Pattern>,
+    pub images: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+}
+
+impl ContainerSelector {
+    /// Selector for a single container name (exact, glob or regex).
+    pub fn name(pattern: &str) -> Result<Self, String> {
+        Ok(ContainerSelector::default().with_name(Pattern::parse(pattern)?))
+    }
+
+    pub fn with_name(mut self, pattern: Pattern) -> Self {
+        self.names.push(pattern);
+        self
+    }
+    pub fn with_image(mut self, pattern: Pattern) -> Self {
+        self.images.push(pattern);
+        self
+    }
+    pub fn with_exclude(mut self, pattern: Pattern) -> Self {
+        self.exclude.push(pattern);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.images.is_empty()
+    }
+
+    pub fn matches(&self, name: &str, image: Option<&str>) -> bool {
+        let image = image.unwrap_or_default();
+
+        let name_ok = self.names.is_empty() || self.names.iter().any(|p| p.matches(name));
+        let image_ok = self.images.is_empty() || self.images.iter().any(|p| p.matches(image));
+        let excluded = self
+            .exclude
+            .iter()
+            .any(|p| p.matches(name) || p.matches(image));
+
+        name_ok && image_ok && !excluded
+    }
+}
+
+impl fmt::Display for ContainerSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |patterns: &[Pattern]| {
+            patterns
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join("|")
+        };
+
+        write!(f, "name={}", join(&self.names))?;
+        if !self.images.is_empty() {
+            write!(f, " image={}", join(&self.images))?;
+        }
+        if !self.exclude.is_empty() {
+            write!(f, " exclude={}", join(&self.exclude))?;
+        }
+        Ok(())
+    }
+}
+
+/// Container list of the pod spec a rule is applied to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ContainerList {
+    Containers,
+    InitContainers,
+    /// Init containers with `restartPolicy: Always` (Kubernetes 1.28+).
+    NativeSidecars,
+    /// Only reachable through the `pods/ephemeralcontainers` subresource.
+    EphemeralContainers,
+}
+
+impl ContainerList {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "containers" => Ok(ContainerList::Containers),
+            "initContainers" => Ok(ContainerList::InitContainers),
+            "sidecars" | "nativeSidecars" => Ok(ContainerList::NativeSidecars),
+            "ephemeralContainers" => Ok(ContainerList::EphemeralContainers),
+            _ => Err(format!("unknown container list {}", value)),
+        }
+    }
+
+    /// Name of the pod spec field holding the list, used in JSON Patch paths.
+    pub fn field(&self) -> &'static str {
+        match self {
+            ContainerList::Containers => "containers",
+            ContainerList::InitContainers | ContainerList::NativeSidecars => "initContainers",
+            ContainerList::EphemeralContainers => "ephemeralContainers",
+        }
+    }
+}
+
+impl fmt::Display for ContainerList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainerList::NativeSidecars => write!(f, "sidecars"),
+            _ => write!(f, "{}", self.field()),
+        }
+    }
+}
+
+/// Evaluates a Kubernetes label selector. An empty selector matches
+/// everything.
+pub fn label_selector_matches(
+    selector: &LabelSelector,
+    labels: Option<&BTreeMap<String, String>>,
+) -> bool {
+    let get = |key: &String| labels.and_then(|l| l.get(key));
+
+    let labels_ok = selector
+        .match_labels
+        .iter()
+        .flatten()
+        .all(|(k, v)| get(k) == Some(v));
+
+    let expressions_ok = selector.match_expressions.iter().flatten().all(|req| {
+        let values = req.values.as_deref().unwrap_or_default();
+        match req.operator.as_str() {
+            "In" => get(&req.key).is_some_and(|v| values.contains(v)),
+            "NotIn" => get(&req.key).is_none_or(|v| !values.contains(v)),
+            "Exists" => get(&req.key).is_some(),
+            "DoesNotExist" => get(&req.key).is_none(),
+            _ => false,
+        }
+    });
+
+    labels_ok && expressions_ok
+}
+
+/// Checks the operators and values of a label selector the same way the API
+/// server does.
+pub fn validate_label_selector(selector: &LabelSelector) -> Result<(), String> {
+    for LabelSelectorRequirement {
+        key,
+        operator,
+        values,
+    } in selector.match_expressions.iter().flatten()
+    {
+        let has_values = values.as_ref().is_some_and(|v| !v.is_empty());
+        match operator.as_str() {
+            "In" | "NotIn" if !has_values => {
+                return Err(format!("{} {} needs at least one value", key, operator));
+            }
+            "Exists" | "DoesNotExist" if has_values => {
+                return Err(format!("{} {} must not have values", key, operator));
+            }
+            "In" | "NotIn" | "Exists" | "DoesNotExist" => {}
+            _ => return Err(format!("unknown selector operator {}", operator)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Namespaces which are never mutated, whatever the configuration says.
+pub const SYSTEM_NAMESPACES: [&str; 3] = ["kube-system", "kube-public", "kube-node-lease"];
+
+/// Namespace allow/deny lists evaluated inside the webhook, independently of
+/// the `namespaceSelector` of the WebhookConfiguration.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NamespaceFilter {
+    /// When not empty, only matching namespaces are mutated.
+    pub allow: Vec<Pattern>,
+    pub deny: Vec<Pattern>,
+}
+
+impl NamespaceFilter {
+    pub fn with_allow(mut self, pattern: Pattern) -> Self {
+        self.allow.push(pattern);
+        self
+    }
+    pub fn with_deny(mut self, pattern: Pattern) -> Self {
+        self.deny.push(pattern);
+        self
+    }
+
+    pub fn allows(&self, namespace: &str) -> bool {
+        if SYSTEM_NAMESPACES.contains(&namespace) {
+            return false;
+        }
+        if self.deny.iter().any(|p| p.matches(namespace)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|p| p.matches(namespace))
+    }
+}
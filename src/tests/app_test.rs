@@ -1,5 +1,6 @@
-use crate::app::{AppState, Container};
-use crate::config::{Config, ContainerPatch};
+use crate::app::AppState;
+use crate::config::{Config, ContainerPatch, PortPatch, Rule};
+use crate::selector::ContainerSelector;
 
 fn load_config() -> Config {
     Config::default()
@@ -20,22 +21,25 @@ async fn test_app_state_build() {
     let config = load_config();
     let app_state = AppState::build(&config);
 
-    assert_eq!(app_state.container_properties.name, "app-container");
-    assert_eq!(app_state.container_properties.port_name, "http");
-    assert_eq!(app_state.container_properties.port_number, 8080);
+    assert_eq!(app_state.rules.len(), 1);
+    assert_eq!(
+        app_state.rules[0].container.to_string(),
+        "name=app-container"
+    );
+    assert_eq!(app_state.rules[0].ports, vec![PortPatch::new("http", 8080)]);
 }
 
 #[tokio::test]
-async fn test_app_state_container_properties() {
-    let config = load_config();
+async fn test_app_state_rules() {
+    let config = load_config().with_rules(vec![
+        Rule::new("envoy")
+            .with_container(ContainerSelector::name("envoy-sidecar").unwrap())
+            .with_port(PortPatch::new("envoy-metrics", 20200)),
+        Rule::new("app")
+            .with_container(ContainerSelector::name("app-container").unwrap())
+            .with_port(PortPatch::new("http", 8080)),
+    ]);
     let app_state = AppState::build(&config);
 
-    assert_eq!(
-        app_state.container_properties,
-        Container {
-            name: "app-container".to_string(),
-            port_name: "http".to_string(),
-            port_number: 8080,
-        }
-    );
+    assert_eq!(app_state.rules.as_slice(), config.rules.as_slice());
 }
@@ -1,4 +1,10 @@
-use crate::config::{Config, ServerCertificate};
+use crate::config::{
+    AnnotationConfig, CollisionPolicy, Config, ConfigLoader, ConflictPolicy, ContainerInjection,
+    EnvMerge, EnvPatch, FileConfigLoader, InjectionMode, MeshPreset, Monitoring, MonitoringPreset,
+    PatchConfig, PatchFailurePolicy, PortPatch, PortSource, Protocol, ServerCertificate,
+};
+use crate::selector::{ContainerList, NamespaceFilter, Pattern};
+use crate::workload::CustomResource;
 
 use std::fs;
 use tempfile::tempdir;
@@ -57,6 +63,18 @@ o7R7oq6hcPDqe3fd78WSCWw=
 -----END PRIVATE KEY-----
 "#;
 
+/// Loads `yaml` through the file loader, like the config of a deployment.
+fn load_yaml(yaml: &str) -> Config {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, yaml).unwrap();
+
+    FileConfigLoader {
+        path: path.to_str().unwrap().to_string(),
+    }
+    .load()
+}
+
 fn load_config() -> Config {
     Config::default()
         .with_addr("127.0.0.1")
@@ -109,3 +127,509 @@ fn test_config_tls_key() {
     let config = load_config();
     assert_eq!(config.key_path, "/etc/webhook/key.pem");
 }
+
+#[test]
+fn test_config_rules_from_file() {
+    let config = load_yaml(
+        r#"
+rules:
+  - name: envoy
+    container: envoy-sidecar
+    selector:
+      app: web
+    ports:
+      - name: envoy-metrics
+        number: 20200
+  - container: app
+    on_collision: next
+    on_conflict: rename
+    ports:
+      - name: metrics
+        number: 9200
+      - name: admin
+        number: 9901
+      - name: exporter
+        range: 9100-9199
+      - name: statsd
+        number: 8125
+        protocol: udp
+        host_port: 18125
+        host_ip: 127.0.0.1
+"#,
+    );
+
+    assert_eq!(config.rules.len(), 2);
+    assert_eq!(config.rules[0].name, "envoy");
+    assert_eq!(config.rules[0].container.to_string(), "name=envoy-sidecar");
+    assert_eq!(
+        config.rules[0]
+            .selector
+            .match_labels
+            .as_ref()
+            .unwrap()
+            .get("app")
+            .unwrap(),
+        "web"
+    );
+    assert_eq!(config.rules[1].name, "rule-1");
+    assert_eq!(config.rules[1].targets, vec![ContainerList::Containers]);
+    assert_eq!(config.rules[0].on_collision, CollisionPolicy::Skip);
+    assert_eq!(config.rules[1].on_collision, CollisionPolicy::Next);
+    assert_eq!(config.rules[0].on_conflict, ConflictPolicy::Skip);
+    assert_eq!(config.rules[1].on_conflict, ConflictPolicy::Rename);
+    assert_eq!(
+        config.rules[1].ports,
+        vec![
+            PortPatch::new("metrics", 9200),
+            PortPatch::new("admin", 9901),
+            PortPatch::new("exporter", 0).with_range(9100, 9199),
+            PortPatch::new("statsd", 8125)
+                .with_protocol(Protocol::Udp)
+                .with_host_port(18125)
+                .with_host_ip("127.0.0.1"),
+        ]
+    );
+}
+
+#[test]
+fn test_config_legacy_container_patch() {
+    let config = load_yaml(
+        "container_patch:\n  name: simple-api\n  port_name: metrics\n  port_number: 9101\n",
+    );
+
+    assert_eq!(config.rules.len(), 1);
+    assert_eq!(config.rules[0].container.to_string(), "name=simple-api");
+    assert_eq!(config.rules[0].ports, vec![PortPatch::new("metrics", 9101)]);
+}
+
+#[test]
+fn test_config_container_selector_mapping() {
+    let config = load_yaml(
+        r#"
+rules:
+  - name: envoy
+    container:
+      name: ["envoy-*", "re:consul-(dataplane|sidecar)"]
+      image: "*/envoy:*"
+      exclude: "*-init"
+    targets: [containers, sidecars]
+    ports:
+      - name: envoy-metrics
+        number: 20200
+"#,
+    );
+
+    assert_eq!(
+        config.rules[0].container.to_string(),
+        "name=envoy-*|re:consul-(dataplane|sidecar) image=*/envoy:* exclude=*-init"
+    );
+    assert_eq!(
+        config.rules[0].targets,
+        vec![ContainerList::Containers, ContainerList::NativeSidecars]
+    );
+}
+
+#[test]
+#[should_panic(expected = "out of range")]
+fn test_config_port_number_out_of_range() {
+    load_yaml(
+        "rules:\n  - container: app\n    ports:\n      - name: metrics\n        number: 70000\n",
+    );
+}
+
+#[test]
+#[should_panic(expected = "rule rule-0: Metrics_Port is not a valid port name")]
+fn test_config_rejects_invalid_port_name() {
+    load_yaml(
+        "rules:\n  - container: app\n    ports:\n      - name: Metrics_Port\n        number: 9102\n",
+    );
+}
+
+#[test]
+#[should_panic(expected = "rule rule-0: prometheus-metrics is not a valid port name")]
+fn test_config_rejects_long_port_name() {
+    load_yaml(
+        "rules:\n  - container: app\n    ports:\n      - name: prometheus-metrics\n        number: 9102\n",
+    );
+}
+
+#[test]
+#[should_panic(expected = "app_protocol is only defined on Service ports")]
+fn test_config_rejects_app_protocol() {
+    load_yaml(
+        "rules:\n  - container: app\n    ports:\n      - name: web\n        number: 8080\n        app_protocol: http\n",
+    );
+}
+
+#[test]
+fn test_config_annotations() {
+    let config = load_yaml(
+        r#"
+annotations:
+  prefix: example.com
+  key: inject-ports
+  values: [enabled, "yes"]
+  disabled_values: disabled
+  mode: opt-out
+"#,
+    );
+
+    assert_eq!(
+        config.annotations,
+        AnnotationConfig::default()
+            .with_prefix("example.com")
+            .with_key("inject-ports")
+            .with_enabled_values(vec!["enabled".to_string(), "yes".to_string()])
+            .with_disabled_values(vec!["disabled".to_string()])
+            .with_mode(InjectionMode::OptOut)
+    );
+    assert_eq!(
+        config.annotations.injector_key(),
+        "example.com/inject-ports"
+    );
+}
+
+#[test]
+fn test_config_match_expressions_and_namespaces() {
+    let config = load_yaml(
+        r#"
+namespaces:
+  allow: ["team-*"]
+  deny: team-legacy
+rules:
+  - container: app
+    selector:
+      matchLabels:
+        app: web
+      matchExpressions:
+        - key: tier
+          operator: NotIn
+          values: [batch]
+    ports:
+      - name: metrics
+        number: 9102
+"#,
+    );
+
+    let selector = &config.rules[0].selector;
+    assert_eq!(selector.match_labels.as_ref().unwrap()["app"], "web");
+    let expressions = selector.match_expressions.as_ref().unwrap();
+    assert_eq!(expressions[0].operator, "NotIn");
+    assert_eq!(
+        config.namespaces,
+        NamespaceFilter::default()
+            .with_allow(Pattern::parse("team-*").unwrap())
+            .with_deny(Pattern::parse("team-legacy").unwrap())
+    );
+}
+
+#[test]
+#[should_panic(expected = "rule rule-0: tier In needs at least one value")]
+fn test_config_invalid_match_expression() {
+    load_yaml(
+        "rules:\n  - container: app\n    selector:\n      matchExpressions:\n        - key: tier\n          operator: In\n",
+    );
+}
+
+#[test]
+fn test_config_invalid_namespace_pattern() {
+    let panic = std::panic::catch_unwind(|| load_yaml("namespaces:\n  deny: [\"re:team-(\"]\n"))
+        .unwrap_err();
+    let message = panic.downcast_ref::<String>().unwrap();
+
+    // not reported as a rule
+    assert!(message.starts_with("namespaces: invalid regex team-("));
+}
+
+#[test]
+fn test_config_custom_resources() {
+    let config = load_yaml(
+        r#"
+custom_resources:
+  - group: argoproj.io
+    version: v1alpha1
+    kind: Rollout
+    pod_template: /spec/template
+  - group: example.com
+    kind: Worker
+    pod_spec: /spec/worker/podSpec
+"#,
+    );
+
+    assert_eq!(
+        config.custom_resources,
+        vec![
+            CustomResource::pod_template("argoproj.io", "Rollout", "/spec/template")
+                .with_version("v1alpha1"),
+            CustomResource::pod_spec("example.com", "Worker", "/spec/worker/podSpec"),
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "custom resource Worker: exactly one of pod_spec and pod_template")]
+fn test_config_custom_resource_needs_pointer() {
+    load_yaml("custom_resources:\n  - group: example.com\n    kind: Worker\n");
+}
+
+#[test]
+fn test_config_patch() {
+    let config = load_yaml("patch:\n  max_size: 4096\n  failure_policy: deny\n");
+
+    assert_eq!(
+        config.patch,
+        PatchConfig::default()
+            .with_max_size(4096)
+            .with_failure_policy(PatchFailurePolicy::Deny)
+    );
+    assert_eq!(
+        Config::default().patch.failure_policy,
+        PatchFailurePolicy::Allow
+    );
+}
+
+#[test]
+#[should_panic(expected = "patch: unknown patch failure policy ignore")]
+fn test_config_patch_unknown_policy() {
+    load_yaml("patch:\n  failure_policy: ignore\n");
+}
+
+#[test]
+fn test_config_container_injection() {
+    let config = load_yaml(
+        r#"
+rules:
+  - name: "logs"
+    container: "app"
+    inject:
+      - target: "sidecars"
+        container:
+          name: "fluent-bit"
+          image: "fluent/fluent-bit:3.1"
+          volumeMounts:
+            - name: "app-logs"
+              mountPath: "/logs"
+        volumes:
+          - name: "app-logs"
+            emptyDir: {}
+        mounts:
+          - name: "app-logs"
+            mountPath: "/var/log/app"
+  - name: "exporter"
+    inject:
+      - container:
+          name: "node-exporter"
+          image: "prom/node-exporter"
+"#,
+    );
+
+    assert_eq!(
+        config.rules[0].inject,
+        vec![
+            ContainerInjection::new(serde_json::json!({
+                "name": "fluent-bit",
+                "image": "fluent/fluent-bit:3.1",
+                "volumeMounts": [{ "name": "app-logs", "mountPath": "/logs" }]
+            }))
+            .with_list(ContainerList::NativeSidecars)
+            .with_volume(serde_json::json!({ "name": "app-logs", "emptyDir": {} }))
+            .with_mount(serde_json::json!({ "name": "app-logs", "mountPath": "/var/log/app" }))
+        ]
+    );
+    // a rule which only injects a container needs no target container
+    assert!(config.rules[1].container.is_empty());
+    assert_eq!(config.rules[1].inject[0].name(), "node-exporter");
+}
+
+#[test]
+#[should_panic(expected = "rule logs: injected container needs a name")]
+fn test_config_invalid_container_template() {
+    load_yaml(
+        "rules:\n  - name: logs\n    inject:\n      - container:\n          image: fluent/fluent-bit\n",
+    );
+}
+
+#[test]
+fn test_config_env() {
+    let config = load_yaml(
+        r#"
+rules:
+  - name: "metrics"
+    container: "app"
+    ports:
+      - name: "metrics"
+        number: 9100
+    env:
+      - name: "METRICS_PORT"
+        port: "metrics"
+        merge: "override"
+      - name: "POD_NAME"
+        valueFrom:
+          fieldRef:
+            fieldPath: "metadata.name"
+      - name: "JAVA_OPTS"
+        value: "-javaagent:/agent.jar"
+        merge: "append"
+      - name: "RETRIES"
+        value: 3
+"#,
+    );
+
+    assert_eq!(
+        config.rules[0].env,
+        vec![
+            EnvPatch::port("METRICS_PORT", "metrics").with_merge(EnvMerge::Override),
+            EnvPatch::value_from(
+                "POD_NAME",
+                serde_json::json!({ "fieldRef": { "fieldPath": "metadata.name" } })
+            ),
+            EnvPatch::value("JAVA_OPTS", "-javaagent:/agent.jar").with_merge(EnvMerge::Append),
+            EnvPatch::value("RETRIES", "3"),
+        ]
+    );
+}
+
+#[test]
+#[should_panic(
+    expected = "rule metrics: env POD_NAME needs exactly one of value, valueFrom and port"
+)]
+fn test_config_env_needs_one_source() {
+    load_yaml(
+        "rules:\n  - name: metrics\n    container: app\n    env:\n      - name: POD_NAME\n        value: x\n        port: metrics\n",
+    );
+}
+
+#[test]
+#[should_panic(expected = "rule metrics: unknown env merge replace")]
+fn test_config_env_unknown_merge() {
+    load_yaml(
+        "rules:\n  - name: metrics\n    container: app\n    env:\n      - name: A\n        value: x\n        merge: replace\n",
+    );
+}
+
+#[test]
+fn test_config_port_from() {
+    let config = load_yaml(
+        r#"
+rules:
+  - name: "metrics"
+    container: "app"
+    ports:
+      - name: "metrics"
+        from:
+          arg: "--metrics-addr"
+      - name: "scrape"
+        from:
+          annotation: "prometheus.io/port"
+"#,
+    );
+
+    assert_eq!(
+        config.rules[0].ports,
+        vec![
+            PortPatch::new("metrics", 0).with_source(PortSource::Arg("--metrics-addr".to_string())),
+            PortPatch::new("scrape", 0)
+                .with_source(PortSource::Annotation("prometheus.io/port".to_string())),
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "rule metrics: port metrics takes its number from the container")]
+fn test_config_port_from_with_number() {
+    load_yaml(
+        "rules:\n  - name: metrics\n    container: app\n    ports:\n      - name: metrics\n        number: 9102\n        from:\n          env: METRICS_PORT\n",
+    );
+}
+
+#[test]
+fn test_config_monitoring() {
+    let config = load_yaml(
+        r#"
+rules:
+  - name: "metrics"
+    container: "app"
+    ports:
+      - name: "http"
+        number: 8080
+      - name: "metrics"
+        number: 9102
+    monitoring:
+      - preset: "prometheus"
+        port: "metrics"
+        path: "/prometheus"
+        overwrite: true
+      - preset: "datadog"
+"#,
+    );
+
+    assert_eq!(
+        config.rules[0].monitoring,
+        vec![
+            Monitoring::new(MonitoringPreset::Prometheus)
+                .with_port("metrics")
+                .with_path("/prometheus")
+                .with_overwrite(true),
+            Monitoring::new(MonitoringPreset::Datadog),
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "rule metrics: monitoring port admin is not a port of the rule")]
+fn test_config_monitoring_unknown_port() {
+    load_yaml(
+        "rules:\n  - name: metrics\n    container: app\n    ports:\n      - name: metrics\n        number: 9102\n    monitoring:\n      - preset: prometheus\n        port: admin\n",
+    );
+}
+
+#[test]
+fn test_config_mesh_preset() {
+    let config = load_yaml(
+        r#"
+rules:
+  - name: "consul"
+    preset: "consul"
+  - name: "app"
+    preset: "istio"
+    container: "app"
+    ports:
+      - name: "metrics"
+        number: 9102
+  - name: "istio"
+    targets: "containers"
+    preset: "istio"
+"#,
+    );
+
+    let consul = &config.rules[0];
+    assert_eq!(consul.container, MeshPreset::Consul.selector());
+    assert!(
+        consul
+            .container
+            .matches("consul-dataplane", Some("hashicorp/consul-dataplane:1.6"))
+    );
+    assert!(
+        consul
+            .container
+            .matches("envoy-sidecar", Some("envoyproxy/envoy:v1.31"))
+    );
+    assert_eq!(
+        consul.targets,
+        vec![ContainerList::Containers, ContainerList::NativeSidecars]
+    );
+    assert_eq!(consul.ports, MeshPreset::Consul.ports());
+
+    let containers_only = &config.rules[2];
+    assert_eq!(containers_only.container, MeshPreset::Istio.selector());
+    assert_eq!(containers_only.targets, vec![ContainerList::Containers]);
+
+    // the rule's own container and ports win over the preset
+    let app = &config.rules[1];
+    assert!(app.container.matches("app", None));
+    assert_eq!(app.ports, vec![PortPatch::new("metrics", 9102)]);
+    assert_eq!(
+        app.exclude_annotation.as_deref(),
+        Some("traffic.sidecar.istio.io/excludeInboundPorts")
+    );
+}
@@ -0,0 +1,71 @@
+use crate::diff::{diff, diff_member};
+use crate::jsonpatch::PatchOp;
+
+use serde_json::{Value, json};
+
+/// The ops as sent to the API server.
+fn ops(ops: Vec<PatchOp>) -> Value {
+    serde_json::to_value(ops).unwrap()
+}
+
+#[test]
+fn test_diff_objects() {
+    let old = json!({ "a": 1, "b": { "c": "x" }, "gone": true });
+    let new = json!({ "a": 1, "b": { "c": "y", "d/e~f": "z" } });
+
+    assert_eq!(
+        ops(diff(&old, &new)),
+        json!([
+            { "op": "remove", "path": "/gone" },
+            { "op": "replace", "path": "/b/c", "value": "y" },
+            { "op": "add", "path": "/b/d~1e~0f", "value": "z" }
+        ])
+    );
+    assert!(diff(&new, &new).is_empty());
+}
+
+#[test]
+fn test_diff_arrays() {
+    let old = json!({ "ports": [{ "containerPort": 80 }, { "containerPort": 81 }, { "containerPort": 82 }] });
+    let shorter = json!({ "ports": [{ "containerPort": 8080 }] });
+    let longer = json!({ "ports": [
+        { "containerPort": 80 }, { "containerPort": 81 }, { "containerPort": 82 }, { "containerPort": 83 }
+    ] });
+
+    assert_eq!(
+        ops(diff(&old, &shorter)),
+        json!([
+            { "op": "replace", "path": "/ports/0/containerPort", "value": 8080 },
+            { "op": "remove", "path": "/ports/2" },
+            { "op": "remove", "path": "/ports/1" }
+        ])
+    );
+    assert_eq!(
+        ops(diff(&old, &longer)),
+        json!([{ "op": "add", "path": "/ports/-", "value": { "containerPort": 83 } }])
+    );
+}
+
+#[test]
+fn test_diff_type_change_replaces_value() {
+    assert_eq!(
+        ops(diff(&json!({ "a": [1] }), &json!({ "a": { "b": 1 } }))),
+        json!([{ "op": "replace", "path": "/a", "value": { "b": 1 } }])
+    );
+}
+
+#[test]
+fn test_diff_member() {
+    let old = json!({ "spec": {} });
+    let new = json!({ "spec": {}, "metadata": { "annotations": { "a/b": "c" } } });
+
+    assert!(diff_member("spec", &old, &new).is_empty());
+    assert_eq!(
+        ops(diff_member("metadata", &old, &new)),
+        json!([{ "op": "add", "path": "/metadata", "value": { "annotations": { "a/b": "c" } } }])
+    );
+    assert_eq!(
+        ops(diff_member("metadata", &new, &old)),
+        json!([{ "op": "remove", "path": "/metadata" }])
+    );
+}
@@ -0,0 +1,158 @@
+use crate::jsonpatch::{PatchOp, Pointer, apply, escape_pointer};
+
+use serde_json::{Value, json};
+
+fn patch(ops: Value) -> Vec<PatchOp> {
+    serde_json::from_value(ops).unwrap()
+}
+
+#[test]
+fn test_escape_pointer() {
+    assert_eq!(escape_pointer("app"), "app");
+    assert_eq!(
+        escape_pointer("syscallx86.com/injected-ports"),
+        "syscallx86.com~1injected-ports"
+    );
+    // ~ first, otherwise the ~ of ~1 would be escaped again
+    assert_eq!(escape_pointer("a~/b"), "a~0~1b");
+}
+
+#[test]
+fn test_pointer_builder() {
+    let pointer = Pointer::root()
+        .key("metadata")
+        .key("annotations")
+        .key("syscallx86.com/status");
+    assert_eq!(
+        pointer.as_str(),
+        "/metadata/annotations/syscallx86.com~1status"
+    );
+    assert_eq!(
+        pointer.tokens().collect::<Vec<_>>(),
+        vec!["metadata", "annotations", "syscallx86.com/status"]
+    );
+    assert_eq!(Pointer::root().key("ports").index(2).as_str(), "/ports/2");
+    assert_eq!(Pointer::root().key("ports").end().as_str(), "/ports/-");
+}
+
+#[test]
+fn test_pointer_parse() {
+    assert_eq!(Pointer::parse("").unwrap(), Pointer::root());
+    assert!(Pointer::parse("/spec/template").is_ok());
+    assert!(Pointer::parse("/a~0b~1c").is_ok());
+    assert!(Pointer::parse("spec").is_err());
+    assert!(Pointer::parse("/a~2").is_err());
+    assert!(Pointer::parse("/a~").is_err());
+    assert!(serde_json::from_value::<PatchOp>(json!({ "op": "remove", "path": "spec" })).is_err());
+}
+
+#[test]
+fn test_pointer_strip_prefix() {
+    let spec = Pointer::root().key("spec");
+    let template = Pointer::parse("/spec/template").unwrap();
+
+    assert_eq!(
+        Pointer::parse("/spec/containers/0")
+            .unwrap()
+            .strip_prefix(&spec),
+        Some(Pointer::parse("/containers/0").unwrap())
+    );
+    assert_eq!(spec.strip_prefix(&spec), Some(Pointer::root()));
+    // only whole tokens are stripped
+    assert_eq!(
+        Pointer::parse("/specification")
+            .unwrap()
+            .strip_prefix(&spec),
+        None
+    );
+    assert_eq!(
+        Pointer::parse("/containers").unwrap().under(&template),
+        Pointer::parse("/spec/template/containers").unwrap()
+    );
+}
+
+#[test]
+fn test_patch_op_serde() {
+    let ops = json!([
+        { "op": "add", "path": "/a", "value": 1 },
+        { "op": "remove", "path": "/b" },
+        { "op": "replace", "path": "/c", "value": "x" },
+        { "op": "test", "path": "/c", "value": "x" },
+        { "op": "move", "from": "/c", "path": "/d" },
+        { "op": "copy", "from": "/d", "path": "/e" }
+    ]);
+
+    let parsed = patch(ops.clone());
+    assert_eq!(
+        parsed[4],
+        PatchOp::Move {
+            from: Pointer::root().key("c"),
+            path: Pointer::root().key("d"),
+        }
+    );
+    assert_eq!(serde_json::to_value(&parsed).unwrap(), ops);
+}
+
+#[test]
+fn test_apply() {
+    let mut doc = json!({
+        "metadata": { "annotations": { "a/b": "c" } },
+        "spec": { "containers": [{ "name": "app" }, { "name": "envoy" }] }
+    });
+
+    apply(
+        &mut doc,
+        &patch(json!([
+            { "op": "add", "path": "/spec/containers/1", "value": { "name": "init" } },
+            { "op": "add", "path": "/spec/containers/-", "value": { "name": "last" } },
+            { "op": "remove", "path": "/spec/containers/0" },
+            { "op": "replace", "path": "/metadata/annotations/a~1b", "value": "d" },
+            { "op": "test", "path": "/metadata/annotations/a~1b", "value": "d" },
+            { "op": "copy", "from": "/spec/containers/0", "path": "/spec/first" },
+            { "op": "move", "from": "/spec/first", "path": "/metadata/first" }
+        ])),
+    )
+    .unwrap();
+
+    assert_eq!(
+        doc,
+        json!({
+            "metadata": { "annotations": { "a/b": "d" }, "first": { "name": "init" } },
+            "spec": { "containers": [{ "name": "init" }, { "name": "envoy" }, { "name": "last" }] }
+        })
+    );
+}
+
+#[test]
+fn test_apply_errors() {
+    let doc = json!({ "spec": { "containers": [{ "name": "app" }] } });
+    let fails = |ops: Value| {
+        let mut doc = doc.clone();
+        apply(&mut doc, &patch(ops)).unwrap_err()
+    };
+
+    assert_eq!(
+        fails(json!([{ "op": "replace", "path": "/spec/hostNetwork", "value": true }])),
+        "/spec/hostNetwork does not exist"
+    );
+    assert_eq!(
+        fails(json!([{ "op": "add", "path": "/spec/volumes/0", "value": {} }])),
+        "/spec/volumes does not exist"
+    );
+    assert_eq!(
+        fails(json!([{ "op": "add", "path": "/spec/containers/01", "value": {} }])),
+        "/spec/containers/01 is not a valid array index"
+    );
+    assert_eq!(
+        fails(json!([{ "op": "remove", "path": "/spec/containers/1" }])),
+        "/spec/containers/1 does not exist"
+    );
+    assert_eq!(
+        fails(json!([{ "op": "test", "path": "/spec/containers/0/name", "value": "envoy" }])),
+        "test of /spec/containers/0/name failed"
+    );
+    assert_eq!(
+        fails(json!([{ "op": "move", "from": "/spec", "path": "/spec/containers/0/spec" }])),
+        "can't move /spec into itself"
+    );
+}
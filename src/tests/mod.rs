@@ -1,2 +1,8 @@
 mod app_test;
 mod config_tests;
+mod diff_tests;
+mod jsonpatch_tests;
+mod overrides_tests;
+mod selector_tests;
+mod webhook_tests;
+mod workload_tests;
@@ -0,0 +1,130 @@
+use crate::config::{AnnotationConfig, PortPatch, Protocol, Rule};
+use crate::overrides::{apply_overrides, is_valid_port_name, parse_ports};
+use crate::selector::ContainerSelector;
+
+use std::collections::BTreeMap;
+
+fn rules() -> Vec<Rule> {
+    vec![
+        Rule::new("app")
+            .with_container(ContainerSelector::name("app").unwrap())
+            .with_selector("team", "web")
+            .with_port(PortPatch::new("metrics", 9200)),
+        Rule::new("envoy")
+            .with_container(ContainerSelector::name("envoy").unwrap())
+            .with_port(PortPatch::new("envoy-metrics", 20200)),
+    ]
+}
+
+fn annotations(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+    entries
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_parse_ports() {
+    assert_eq!(
+        parse_ports("metrics:9102/TCP, statsd:8125/udp,admin:9901").unwrap(),
+        vec![
+            PortPatch::new("metrics", 9102),
+            PortPatch::new("statsd", 8125).with_protocol(Protocol::Udp),
+            PortPatch::new("admin", 9901),
+        ]
+    );
+}
+
+#[test]
+fn test_parse_ports_errors() {
+    assert!(parse_ports("").is_err());
+    assert!(parse_ports("metrics").unwrap_err().contains("format"));
+    assert!(
+        parse_ports("metrics:70000")
+            .unwrap_err()
+            .contains("not a valid port number")
+    );
+    assert!(parse_ports("metrics:9102/HTTP").is_err());
+    assert!(
+        parse_ports("Metrics:9102")
+            .unwrap_err()
+            .contains("not a valid port name")
+    );
+}
+
+#[test]
+fn test_port_name_validation() {
+    assert!(is_valid_port_name("metrics-2"));
+    assert!(!is_valid_port_name("envoy-metrics-xx"));
+    assert!(!is_valid_port_name("-metrics"));
+    assert!(!is_valid_port_name("m--etrics"));
+    assert!(!is_valid_port_name("9102"));
+}
+
+#[test]
+fn test_no_override_annotations_keeps_rules() {
+    let overrides = apply_overrides(
+        &rules(),
+        Some(&annotations(&[("a", "b")])),
+        &AnnotationConfig::default(),
+    );
+
+    assert_eq!(overrides.rules, rules());
+    assert!(overrides.warnings.is_empty());
+}
+
+#[test]
+fn test_override_default_rule() {
+    let annotations = annotations(&[
+        ("syscallx86.com/container", "envoy"),
+        ("syscallx86.com/ports", "metrics:9102/TCP,admin:9901"),
+    ]);
+
+    let overrides = apply_overrides(&rules(), Some(&annotations), &AnnotationConfig::default());
+
+    assert!(overrides.warnings.is_empty());
+    assert_eq!(overrides.rules[0].container.to_string(), "name=envoy");
+    assert_eq!(
+        overrides.rules[0].ports,
+        vec![
+            PortPatch::new("metrics", 9102),
+            PortPatch::new("admin", 9901)
+        ]
+    );
+    assert_eq!(overrides.rules[0].selector, Default::default());
+    assert_eq!(overrides.rules[1], rules()[1]);
+}
+
+#[test]
+fn test_invalid_override_falls_back_with_warning() {
+    let annotations = annotations(&[
+        ("syscallx86.com/container", "sidecar"),
+        ("syscallx86.com/ports", "metrics:nope"),
+    ]);
+
+    let overrides = apply_overrides(&rules(), Some(&annotations), &AnnotationConfig::default());
+
+    assert_eq!(overrides.rules[0].container.to_string(), "name=sidecar");
+    assert_eq!(overrides.rules[0].ports, rules()[0].ports);
+    assert_eq!(
+        overrides.warnings,
+        vec!["syscallx86.com/ports: nope is not a valid port number".to_string()]
+    );
+}
+
+#[test]
+fn test_override_keys_follow_prefix() {
+    let config = AnnotationConfig::default().with_prefix("example.com");
+    let annotations = annotations(&[
+        ("syscallx86.com/container", "ignored"),
+        ("example.com/ports", "metrics:9102"),
+    ]);
+
+    let overrides = apply_overrides(&rules(), Some(&annotations), &config);
+
+    assert_eq!(overrides.rules[0].container.to_string(), "name=app");
+    assert_eq!(
+        overrides.rules[0].ports,
+        vec![PortPatch::new("metrics", 9102)]
+    );
+}
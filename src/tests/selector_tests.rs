@@ -0,0 +1,158 @@
+use crate::selector::{
+    ContainerList, ContainerSelector, NamespaceFilter, Pattern, label_selector_matches,
+    validate_label_selector,
+};
+
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use serde_json::json;
+use std::collections::BTreeMap;
+
+fn label_selector(v: serde_json::Value) -> LabelSelector {
+    serde_json::from_value(v).unwrap()
+}
+
+fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_pattern_kinds() {
+    assert!(matches!(Pattern::parse("app").unwrap(), Pattern::Exact(_)));
+    assert!(matches!(Pattern::parse("app-*").unwrap(), Pattern::Glob(_)));
+    assert!(matches!(
+        Pattern::parse("re:app-\\d+").unwrap(),
+        Pattern::Regex(_)
+    ));
+    assert!(Pattern::parse("re:app-(").is_err());
+}
+
+#[test]
+fn test_pattern_matches_whole_value() {
+    let re = Pattern::parse("re:envoy").unwrap();
+    assert!(re.matches("envoy"));
+    assert!(!re.matches("envoy-sidecar"));
+
+    let glob = Pattern::parse("*/envoy:*").unwrap();
+    assert!(glob.matches("docker.io/envoyproxy/envoy:v1.31"));
+    assert!(!glob.matches("envoy:v1.31"));
+
+    assert!(!Pattern::parse("app").unwrap().matches("app-2"));
+}
+
+#[test]
+fn test_pattern_display_roundtrip() {
+    for p in ["app", "envoy-*", "re:app-\\d+"] {
+        assert_eq!(Pattern::parse(p).unwrap().to_string(), p);
+    }
+}
+
+#[test]
+fn test_container_selector_name_and_image() {
+    let selector = ContainerSelector::name("envoy*")
+        .unwrap()
+        .with_image(Pattern::parse("*/envoy:*").unwrap());
+
+    assert!(selector.matches("envoy-sidecar", Some("envoyproxy/envoy:v1")));
+    assert!(!selector.matches("envoy-sidecar", Some("hashicorp/consul:1.20")));
+    assert!(!selector.matches("app", Some("envoyproxy/envoy:v1")));
+}
+
+#[test]
+fn test_container_selector_exclude_name_or_image() {
+    let selector = ContainerSelector::name("*")
+        .unwrap()
+        .with_exclude(Pattern::parse("istio-*").unwrap())
+        .with_exclude(Pattern::parse("*/proxyv2:*").unwrap());
+
+    assert!(selector.matches("app", Some("registry/app:1")));
+    assert!(!selector.matches("istio-proxy", Some("registry/app:1")));
+    assert!(!selector.matches("proxy", Some("docker.io/istio/proxyv2:1.24")));
+}
+
+#[test]
+fn test_container_selector_without_image() {
+    let selector = ContainerSelector::default().with_image(Pattern::parse("*").unwrap());
+
+    assert!(selector.matches("app", None));
+    assert!(
+        !ContainerSelector::name("app")
+            .unwrap()
+            .matches("envoy", None)
+    );
+}
+
+#[test]
+fn test_container_list_parse() {
+    assert_eq!(
+        ContainerList::parse("sidecars").unwrap(),
+        ContainerList::NativeSidecars
+    );
+    assert_eq!(ContainerList::NativeSidecars.field(), "initContainers");
+    assert_eq!(
+        ContainerList::parse("ephemeralContainers").unwrap().field(),
+        "ephemeralContainers"
+    );
+    assert!(ContainerList::parse("volumes").is_err());
+}
+
+#[test]
+fn test_label_selector_expressions() {
+    let selector = label_selector(json!({
+        "matchLabels": { "app": "web" },
+        "matchExpressions": [
+            { "key": "tier", "operator": "In", "values": ["frontend", "edge"] },
+            { "key": "env", "operator": "NotIn", "values": ["dev"] },
+            { "key": "team", "operator": "Exists" },
+            { "key": "legacy", "operator": "DoesNotExist" }
+        ]
+    }));
+
+    let ok = labels(&[("app", "web"), ("tier", "edge"), ("team", "a")]);
+    assert!(label_selector_matches(&selector, Some(&ok)));
+
+    for (k, v) in [("env", "dev"), ("legacy", "true"), ("tier", "backend")] {
+        let mut l = ok.clone();
+        l.insert(k.to_string(), v.to_string());
+        assert!(!label_selector_matches(&selector, Some(&l)), "{}={}", k, v);
+    }
+    assert!(!label_selector_matches(&selector, None));
+}
+
+#[test]
+fn test_empty_label_selector_matches_everything() {
+    assert!(label_selector_matches(&LabelSelector::default(), None));
+}
+
+#[test]
+fn test_validate_label_selector() {
+    let invalid = [
+        json!({ "key": "a", "operator": "In" }),
+        json!({ "key": "a", "operator": "Exists", "values": ["x"] }),
+        json!({ "key": "a", "operator": "Gt", "values": ["1"] }),
+    ];
+    for req in invalid {
+        let selector = label_selector(json!({ "matchExpressions": [req] }));
+        assert!(validate_label_selector(&selector).is_err());
+    }
+}
+
+#[test]
+fn test_namespace_filter() {
+    let all = NamespaceFilter::default();
+    assert!(all.allows("default"));
+    assert!(!all.allows("kube-system"));
+
+    let filter = NamespaceFilter::default()
+        .with_allow(Pattern::parse("team-*").unwrap())
+        .with_deny(Pattern::parse("team-legacy").unwrap());
+    assert!(filter.allows("team-a"));
+    assert!(!filter.allows("team-legacy"));
+    assert!(!filter.allows("default"));
+
+    // systémové namespace nejde povolit
+    let system = NamespaceFilter::default().with_allow(Pattern::parse("kube-*").unwrap());
+    assert!(!system.allows("kube-public"));
+}
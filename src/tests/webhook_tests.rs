@@ -0,0 +1,1796 @@
+use crate::app::AppState;
+use crate::config::{
+    AnnotationConfig, CollisionPolicy, Config, ConflictPolicy, ContainerInjection, EnvMerge,
+    EnvPatch, InjectionMode, MeshPreset, Monitoring, MonitoringPreset, PatchConfig,
+    PatchFailurePolicy, PortPatch, PortSource, Protocol, Rule,
+};
+use crate::jsonpatch::{self, PatchOp, Pointer};
+use crate::logging::Logger;
+use crate::selector::{ContainerList, ContainerSelector, Pattern};
+use crate::webhook::{
+    AdmissionRequest, AdmissionResponse, AdmissionReviewRequest, AdmissionVersion, Operation,
+    Patch, PatchScope, ToReview, admit, build_patch, is_annotated, parse_review, validate_request,
+    verify_patch,
+};
+use crate::workload::Workload;
+
+use poem::http::StatusCode;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+fn pod(containers: Value) -> Value {
+    pod_with_spec(json!({ "containers": containers }))
+}
+
+fn pod_with_spec(spec: Value) -> Value {
+    json!({
+        "apiVersion": "v1",
+        "kind": "Pod",
+        "metadata": {
+            "name": "test",
+            "labels": { "app": "web" }
+        },
+        "spec": spec
+    })
+}
+
+fn log() -> Arc<Logger> {
+    Arc::new(Logger::build("console"))
+}
+
+async fn build(rules: &[Rule], pod: &Value) -> Result<Vec<Value>, String> {
+    build_scoped(rules, pod, PatchScope::Pod).await
+}
+
+async fn build_scoped(
+    rules: &[Rule],
+    pod: &Value,
+    scope: PatchScope,
+) -> Result<Vec<Value>, String> {
+    build_patch(rules, pod, scope, &AnnotationConfig::default(), log())
+        .await
+        .map(|patch| {
+            patch
+                .ops
+                .iter()
+                .map(|op| serde_json::to_value(op).unwrap())
+                .collect()
+        })
+}
+
+/// Only the container port ops, without the annotations stamped on the pod.
+async fn build_ports(rules: &[Rule], pod: &Value) -> Result<Vec<Value>, String> {
+    build_ports_scoped(rules, pod, PatchScope::Pod).await
+}
+
+async fn build_ports_scoped(
+    rules: &[Rule],
+    pod: &Value,
+    scope: PatchScope,
+) -> Result<Vec<Value>, String> {
+    build_scoped(rules, pod, scope).await.map(|ops| {
+        ops.into_iter()
+            .filter(|op| op["path"].as_str().is_some_and(|p| p.starts_with("/spec/")))
+            .collect()
+    })
+}
+
+async fn patch_of(rules: &[Rule], pod: &Value) -> Patch {
+    build_patch(
+        rules,
+        pod,
+        PatchScope::Pod,
+        &AnnotationConfig::default(),
+        log(),
+    )
+    .await
+    .unwrap()
+}
+
+async fn warnings(rules: &[Rule], pod: &Value) -> Vec<String> {
+    patch_of(rules, pod).await.warnings
+}
+
+fn metrics_rule() -> Rule {
+    Rule::new("app")
+        .with_container(ContainerSelector::name("app").unwrap())
+        .with_port(PortPatch::new("metrics", 9200))
+}
+
+fn envoy_rule() -> Rule {
+    Rule::new("envoy")
+        .with_container(ContainerSelector::name("envoy").unwrap())
+        .with_port(PortPatch::new("envoy-metrics", 20200))
+}
+
+#[tokio::test]
+async fn test_single_rule_adds_ports_array() {
+    let pod = pod(json!([{ "name": "app" }]));
+
+    let patch = build_ports(&[metrics_rule()], &pod).await.unwrap();
+
+    assert_eq!(
+        patch,
+        vec![json!({
+            "op": "add",
+            "path": "/spec/containers/0/ports",
+            "value": [{ "name": "metrics", "containerPort": 9200, "protocol": "TCP" }]
+        })]
+    );
+}
+
+#[tokio::test]
+async fn test_rules_for_different_containers_are_combined() {
+    let pod = pod(json!([
+        { "name": "app", "ports": [{ "containerPort": 8080 }] },
+        { "name": "envoy" }
+    ]));
+
+    let patch = build_ports(&[metrics_rule(), envoy_rule()], &pod)
+        .await
+        .unwrap();
+
+    assert_eq!(patch.len(), 2);
+    assert_eq!(patch[0]["path"], "/spec/containers/0/ports/-");
+    assert_eq!(patch[0]["value"]["containerPort"], 9200);
+    assert_eq!(patch[1]["path"], "/spec/containers/1/ports");
+    assert_eq!(patch[1]["value"][0]["containerPort"], 20200);
+}
+
+#[tokio::test]
+async fn test_second_rule_appends_to_ports_added_by_first() {
+    let pod = pod(json!([{ "name": "app" }]));
+    let admin = Rule::new("admin")
+        .with_container(ContainerSelector::name("app").unwrap())
+        .with_port(PortPatch::new("admin", 9901));
+
+    let patch = build_ports(&[metrics_rule(), admin], &pod).await.unwrap();
+
+    // both ports end up in the array the first rule creates
+    assert_eq!(patch.len(), 1);
+    assert_eq!(patch[0]["path"], "/spec/containers/0/ports");
+    assert_eq!(patch[0]["value"][1]["name"], "admin");
+}
+
+#[tokio::test]
+async fn test_duplicate_rules_inject_port_once() {
+    let pod = pod(json!([{ "name": "app" }]));
+
+    let patch = build_ports(&[metrics_rule(), metrics_rule()], &pod)
+        .await
+        .unwrap();
+
+    assert_eq!(patch.len(), 1);
+}
+
+#[tokio::test]
+async fn test_rule_selector_filters_pods() {
+    let pod = pod(json!([{ "name": "app" }, { "name": "envoy" }]));
+    let rules = [
+        metrics_rule().with_selector("app", "api"),
+        envoy_rule().with_selector("app", "web"),
+    ];
+
+    let patch = build_ports(&rules, &pod).await.unwrap();
+
+    assert_eq!(patch.len(), 1);
+    assert_eq!(patch[0]["path"], "/spec/containers/1/ports");
+}
+
+#[tokio::test]
+async fn test_rule_match_expressions() {
+    let pod = pod(json!([{ "name": "app" }]));
+    let selector = |operator: &str| {
+        serde_json::from_value(json!({
+            "matchExpressions": [{ "key": "app", "operator": operator, "values": ["web"] }]
+        }))
+        .unwrap()
+    };
+
+    let rules = [metrics_rule().with_label_selector(selector("NotIn"))];
+    assert!(build_ports(&rules, &pod).await.unwrap().is_empty());
+
+    let rules = [metrics_rule().with_label_selector(selector("In"))];
+    assert_eq!(build_ports(&rules, &pod).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_no_patch_when_nothing_matches() {
+    let pod = pod(json!([
+        { "name": "app", "ports": [{ "containerPort": 9200 }] }
+    ]));
+
+    assert!(
+        build(&[metrics_rule(), envoy_rule()], &pod)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn test_image_selector_patches_every_matching_container() {
+    let pod = pod(json!([
+        { "name": "app", "image": "registry.local/app:1.0" },
+        { "name": "envoy-a", "image": "docker.io/envoyproxy/envoy:v1.31" },
+        { "name": "envoy-b", "image": "docker.io/envoyproxy/envoy:v1.30" }
+    ]));
+    let rule = Rule::new("envoy")
+        .with_container(
+            ContainerSelector::default().with_image(Pattern::parse("*/envoy:*").unwrap()),
+        )
+        .with_port(PortPatch::new("envoy-metrics", 20200))
+        .with_on_collision(CollisionPolicy::Next)
+        .with_on_conflict(ConflictPolicy::Rename);
+
+    let patch = build_ports(&[rule], &pod).await.unwrap();
+
+    assert_eq!(patch.len(), 2);
+    assert_eq!(patch[0]["path"], "/spec/containers/1/ports");
+    assert_eq!(patch[1]["path"], "/spec/containers/2/ports");
+    assert_eq!(patch[1]["value"][0]["containerPort"], 20201);
+}
+
+#[tokio::test]
+async fn test_container_selector_exclusions() {
+    let pod = pod(json!([
+        { "name": "envoy-sidecar", "image": "envoyproxy/envoy:v1.31" },
+        { "name": "envoy-init", "image": "envoyproxy/envoy:v1.31" }
+    ]));
+    let rule = Rule::new("envoy")
+        .with_container(
+            ContainerSelector::default()
+                .with_name(Pattern::parse("re:envoy-.+").unwrap())
+                .with_exclude(Pattern::parse("*-init").unwrap()),
+        )
+        .with_port(PortPatch::new("envoy-metrics", 20200));
+
+    let patch = build_ports(&[rule], &pod).await.unwrap();
+
+    assert_eq!(patch.len(), 1);
+    assert_eq!(patch[0]["path"], "/spec/containers/0/ports");
+}
+
+fn sidecar_pod() -> Value {
+    pod_with_spec(json!({
+        "initContainers": [
+            { "name": "consul-init" },
+            { "name": "consul-dataplane", "restartPolicy": "Always" }
+        ],
+        "containers": [{ "name": "consul-app" }],
+        "ephemeralContainers": [{ "name": "consul-debug" }]
+    }))
+}
+
+fn consul_rule(targets: Vec<ContainerList>) -> Rule {
+    Rule::new("consul")
+        .with_container(ContainerSelector::name("consul-*").unwrap())
+        .with_targets(targets)
+        .with_port(PortPatch::new("envoy-metrics", 20200))
+        .with_on_collision(CollisionPolicy::Next)
+        .with_on_conflict(ConflictPolicy::Rename)
+}
+
+#[tokio::test]
+async fn test_native_sidecars_target_only_restartable_init_containers() {
+    let rule = consul_rule(vec![ContainerList::NativeSidecars]);
+
+    let patch = build_ports(&[rule], &sidecar_pod()).await.unwrap();
+
+    assert_eq!(patch.len(), 1);
+    assert_eq!(patch[0]["path"], "/spec/initContainers/1/ports");
+}
+
+#[tokio::test]
+async fn test_overlapping_target_lists_patch_container_once() {
+    let rule = consul_rule(vec![
+        ContainerList::Containers,
+        ContainerList::InitContainers,
+        ContainerList::NativeSidecars,
+    ]);
+
+    let patch = build_ports(&[rule], &sidecar_pod()).await.unwrap();
+
+    let paths: Vec<&Value> = patch.iter().map(|op| &op["path"]).collect();
+    assert_eq!(
+        paths,
+        vec![
+            "/spec/containers/0/ports",
+            "/spec/initContainers/0/ports",
+            "/spec/initContainers/1/ports"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_ephemeral_containers_only_in_their_scope() {
+    let rule = consul_rule(vec![
+        ContainerList::Containers,
+        ContainerList::EphemeralContainers,
+    ]);
+
+    let patch = build_ports(std::slice::from_ref(&rule), &sidecar_pod())
+        .await
+        .unwrap();
+    assert_eq!(patch.len(), 1);
+    assert_eq!(patch[0]["path"], "/spec/containers/0/ports");
+
+    // ports are rejected by the API server on ephemeral containers
+    assert!(
+        build_ports_scoped(&[rule], &sidecar_pod(), PatchScope::EphemeralContainers)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn test_ephemeral_containers_scope_leaves_metadata_alone() {
+    let rule = Rule::new("debug")
+        .with_container(ContainerSelector::name("consul-debug").unwrap())
+        .with_targets(vec![ContainerList::EphemeralContainers])
+        .with_env(EnvPatch::value("LOG_LEVEL", "debug"));
+
+    let ops = build_scoped(&[rule], &sidecar_pod(), PatchScope::EphemeralContainers)
+        .await
+        .unwrap();
+
+    assert_eq!(ops.len(), 1);
+    assert_eq!(ops[0]["path"], "/spec/ephemeralContainers/0/env");
+}
+
+#[tokio::test]
+async fn test_port_protocol_and_host_fields() {
+    let pod = pod(json!([{ "name": "app" }]));
+    let rule = Rule::new("statsd")
+        .with_container(ContainerSelector::name("app").unwrap())
+        .with_port(PortPatch::new("statsd", 8125).with_protocol(Protocol::Udp))
+        .with_port(
+            PortPatch::new("grpc", 9090)
+                .with_host_port(19090)
+                .with_host_ip("127.0.0.1"),
+        );
+
+    let patch = build(&[rule], &pod).await.unwrap();
+
+    assert_eq!(
+        patch[0]["value"],
+        json!([
+            { "name": "statsd", "containerPort": 8125, "protocol": "UDP" },
+            {
+                "name": "grpc",
+                "containerPort": 9090,
+                "protocol": "TCP",
+                "hostPort": 19090,
+                "hostIP": "127.0.0.1"
+            }
+        ])
+    );
+}
+
+#[tokio::test]
+async fn test_host_network_sets_host_port() {
+    let pod = pod_with_spec(json!({
+        "hostNetwork": true,
+        "containers": [{ "name": "app" }]
+    }));
+    let rule = metrics_rule().with_port(PortPatch::new("admin", 9901).with_host_port(19901));
+
+    let patch = build(&[rule], &pod).await.unwrap();
+
+    assert_eq!(patch[0]["value"][0]["hostPort"], 9200);
+    assert_eq!(patch[0]["value"][1]["hostPort"], 9901);
+}
+
+fn envoy_pod() -> Value {
+    pod(json!([
+        { "name": "app" },
+        { "name": "envoy", "ports": [
+            { "name": "envoy-admin", "containerPort": 9200 },
+            { "name": "statsd", "containerPort": 9201, "protocol": "UDP" }
+        ] }
+    ]))
+}
+
+#[tokio::test]
+async fn test_collision_with_other_container_is_skipped() {
+    let patch = build(&[metrics_rule()], &envoy_pod()).await.unwrap();
+
+    assert!(patch.is_empty());
+}
+
+#[tokio::test]
+async fn test_collision_fail_policy_rejects_pod() {
+    let rule = metrics_rule().with_on_collision(CollisionPolicy::Fail);
+
+    let err = build(&[rule], &envoy_pod()).await.unwrap_err();
+
+    assert!(err.contains("already used by container envoy"));
+}
+
+#[tokio::test]
+async fn test_collision_next_policy_picks_free_port() {
+    let rule = metrics_rule().with_on_collision(CollisionPolicy::Next);
+
+    let patch = build(&[rule], &envoy_pod()).await.unwrap();
+
+    // 9201 is only taken for UDP
+    assert_eq!(patch[0]["value"][0]["containerPort"], 9201);
+}
+
+#[tokio::test]
+async fn test_collision_between_matched_containers() {
+    let pod = pod(json!([{ "name": "app-a" }, { "name": "app-b" }]));
+    let rule = Rule::new("apps")
+        .with_container(ContainerSelector::name("app-*").unwrap())
+        .with_port(PortPatch::new("metrics", 9200))
+        .with_on_collision(CollisionPolicy::Next)
+        .with_on_conflict(ConflictPolicy::Rename);
+
+    let patch = build(&[rule], &pod).await.unwrap();
+
+    assert_eq!(patch[0]["value"][0]["containerPort"], 9200);
+    assert_eq!(patch[1]["value"][0]["containerPort"], 9201);
+    assert_eq!(patch[1]["value"][0]["name"], "metrics-2");
+}
+
+#[tokio::test]
+async fn test_name_collision_with_other_container() {
+    let pod = pod(json!([
+        { "name": "app" },
+        { "name": "exporter", "ports": [{ "name": "metrics", "containerPort": 9100 }] }
+    ]));
+
+    let skipped = build(&[metrics_rule()], &pod).await.unwrap();
+    assert!(skipped.is_empty());
+
+    let rule = metrics_rule().with_on_conflict(ConflictPolicy::Fail);
+    let err = build(&[rule], &pod).await.unwrap_err();
+    assert!(err.contains("port name metrics"));
+
+    // only ports of the target container can be replaced
+    let rule = metrics_rule().with_on_conflict(ConflictPolicy::Replace);
+    let skipped = build(&[rule], &pod).await.unwrap();
+    assert!(skipped.is_empty());
+}
+
+#[tokio::test]
+async fn test_out_of_range_port_does_not_wrap() {
+    // 74736 would wrap to 9200 as u16
+    let pod = pod(json!([
+        { "name": "app", "ports": [{ "name": "bogus", "containerPort": 74736 }] }
+    ]));
+
+    let patch = build(&[metrics_rule()], &pod).await.unwrap();
+
+    assert_eq!(patch[0]["path"], "/spec/containers/0/ports/-");
+}
+
+fn range_rule() -> Rule {
+    Rule::new("exporter")
+        .with_container(ContainerSelector::name("app").unwrap())
+        .with_port(PortPatch::new("metrics", 0).with_range(9100, 9102))
+}
+
+#[tokio::test]
+async fn test_range_allocates_lowest_free_port() {
+    let pod = pod(json!([
+        { "name": "app", "ports": [{ "name": "http", "containerPort": 9100 }] },
+        { "name": "envoy", "ports": [{ "name": "admin", "containerPort": 9101 }] }
+    ]));
+
+    let patch = build(&[range_rule()], &pod).await.unwrap();
+
+    assert_eq!(patch[0]["path"], "/spec/containers/0/ports/-");
+    assert_eq!(patch[0]["value"]["containerPort"], 9102);
+    assert_eq!(
+        patch[1],
+        json!({
+            "op": "add",
+            "path": "/metadata/annotations",
+            "value": {
+                "syscallx86.com/allocated-ports": "metrics=9102",
+                "syscallx86.com/injected-ports": "metrics:9102",
+                "syscallx86.com/status": "injected"
+            }
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_range_allocation_merges_annotation() {
+    let mut pod = pod(json!([{ "name": "app" }]));
+    pod["metadata"]["annotations"] = json!({ "syscallx86.com/allocated-ports": "admin=9300" });
+
+    let patch = build(&[range_rule()], &pod).await.unwrap();
+
+    assert_eq!(
+        patch[1],
+        json!({
+            "op": "replace",
+            "path": "/metadata/annotations/syscallx86.com~1allocated-ports",
+            "value": "admin=9300,metrics=9100"
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_range_allocation_is_stable() {
+    let pod = pod(json!([
+        { "name": "app", "ports": [{ "name": "metrics", "containerPort": 9101 }] }
+    ]));
+
+    let patch = build(&[range_rule()], &pod).await.unwrap();
+
+    assert!(patch.is_empty());
+}
+
+#[tokio::test]
+async fn test_range_exhausted() {
+    let pod = pod(json!([
+        { "name": "app" },
+        { "name": "envoy", "ports": [
+            { "containerPort": 9100 }, { "containerPort": 9101 }, { "containerPort": 9102 }
+        ] }
+    ]));
+
+    let skipped = build(&[range_rule()], &pod).await.unwrap();
+    assert!(skipped.is_empty());
+
+    let rule = range_rule().with_on_collision(CollisionPolicy::Fail);
+    let err = build(&[rule], &pod).await.unwrap_err();
+    assert!(err.contains("no free port left in range 9100-9102"));
+}
+
+fn conflict_pod() -> Value {
+    pod(json!([
+        { "name": "app", "ports": [
+            { "name": "http", "containerPort": 8080 },
+            { "name": "metrics", "containerPort": 9100 }
+        ] }
+    ]))
+}
+
+#[tokio::test]
+async fn test_name_conflict_skip_by_default() {
+    let patch = build(&[metrics_rule()], &conflict_pod()).await.unwrap();
+
+    assert!(patch.is_empty());
+}
+
+#[tokio::test]
+async fn test_name_conflict_replace() {
+    let rule = metrics_rule().with_on_conflict(ConflictPolicy::Replace);
+
+    let patch = build_ports(&[rule], &conflict_pod()).await.unwrap();
+
+    // only the fields which differ from the replaced port are patched
+    assert_eq!(
+        patch,
+        vec![
+            json!({
+                "op": "replace",
+                "path": "/spec/containers/0/ports/1/containerPort",
+                "value": 9200
+            }),
+            json!({
+                "op": "add",
+                "path": "/spec/containers/0/ports/1/protocol",
+                "value": "TCP"
+            })
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_name_conflict_rename() {
+    let rule = metrics_rule().with_on_conflict(ConflictPolicy::Rename);
+
+    let patch = build(std::slice::from_ref(&rule), &conflict_pod())
+        .await
+        .unwrap();
+    assert_eq!(patch[0]["path"], "/spec/containers/0/ports/-");
+    assert_eq!(patch[0]["value"]["name"], "metrics-2");
+
+    // the renamed port is recognized on the next invocation
+    let mut patched = conflict_pod();
+    patched["spec"]["containers"][0]["ports"]
+        .as_array_mut()
+        .unwrap()
+        .push(patch[0]["value"].clone());
+    let again = build(&[rule], &patched).await.unwrap();
+    assert!(again.is_empty());
+}
+
+#[tokio::test]
+async fn test_rename_keeps_iana_name_limit() {
+    let pod = pod(json!([
+        { "name": "app", "ports": [
+            { "name": "envoy-metrics-x", "containerPort": 9100 },
+            { "name": "envoy-metrics-2", "containerPort": 9101 }
+        ] }
+    ]));
+    let rule = Rule::new("envoy")
+        .with_container(ContainerSelector::name("app").unwrap())
+        .with_port(PortPatch::new("envoy-metrics-x", 9200))
+        .with_on_conflict(ConflictPolicy::Rename);
+
+    let patch = build(&[rule], &pod).await.unwrap();
+
+    // "envoy-metrics-" is cut to 13 chars and the trailing hyphen dropped
+    assert_eq!(patch[0]["value"]["name"], "envoy-metrics-3");
+}
+
+fn annotated_pod(annotations: &[(&str, &str)]) -> Value {
+    let mut pod = pod(json!([{ "name": "app" }]));
+    pod["metadata"]["annotations"] = annotations
+        .iter()
+        .map(|(k, v)| (k.to_string(), json!(v)))
+        .collect();
+    pod
+}
+
+#[tokio::test]
+async fn test_is_annotated_default_opt_in() {
+    let config = AnnotationConfig::default();
+
+    let enabled = annotated_pod(&[("syscallx86.com/container-port-injector", "true")]);
+    let disabled = annotated_pod(&[("syscallx86.com/container-port-injector", "yes")]);
+    let missing = pod(json!([{ "name": "app" }]));
+
+    assert!(is_annotated(&enabled, &config, log()).await);
+    assert!(!is_annotated(&disabled, &config, log()).await);
+    assert!(!is_annotated(&missing, &config, log()).await);
+}
+
+#[tokio::test]
+async fn test_is_annotated_custom_key_and_values() {
+    let config = AnnotationConfig::default()
+        .with_prefix("example.com/")
+        .with_key("inject-ports")
+        .with_enabled_values(vec!["enabled".to_string(), "yes".to_string()]);
+
+    let enabled = annotated_pod(&[("example.com/inject-ports", "Yes")]);
+    let old_key = annotated_pod(&[("syscallx86.com/container-port-injector", "true")]);
+
+    assert!(is_annotated(&enabled, &config, log()).await);
+    assert!(!is_annotated(&old_key, &config, log()).await);
+}
+
+#[tokio::test]
+async fn test_is_annotated_opt_out() {
+    let config = AnnotationConfig::default()
+        .with_mode(InjectionMode::OptOut)
+        .with_disabled_values(vec!["false".to_string(), "no".to_string()]);
+
+    let opted_out = annotated_pod(&[("syscallx86.com/container-port-injector", "no")]);
+    let other = annotated_pod(&[("syscallx86.com/container-port-injector", "maybe")]);
+    let missing = pod(json!([{ "name": "app" }]));
+
+    assert!(!is_annotated(&opted_out, &config, log()).await);
+    assert!(is_annotated(&other, &config, log()).await);
+    assert!(is_annotated(&missing, &config, log()).await);
+}
+
+#[tokio::test]
+async fn test_allocated_ports_annotation_uses_prefix() {
+    let config = AnnotationConfig::default().with_prefix("example.com");
+    let pod = pod(json!([{ "name": "app" }]));
+
+    let patch = build_patch(&[range_rule()], &pod, PatchScope::Pod, &config, log())
+        .await
+        .unwrap()
+        .ops;
+
+    assert_eq!(
+        patch[1],
+        PatchOp::add(
+            Pointer::root().key("metadata").key("annotations"),
+            json!({
+                "example.com/allocated-ports": "metrics=9100",
+                "example.com/injected-ports": "metrics:9100",
+                "example.com/status": "injected"
+            })
+        )
+    );
+}
+
+fn admission_state() -> AppState {
+    AppState::build(&Config::default().with_rules(vec![metrics_rule()]))
+}
+
+fn admission_request(operation: &str, kind: Value, object: Option<Value>) -> AdmissionRequest {
+    let mut request = json!({
+        "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+        "kind": kind,
+        "resource": { "group": "", "version": "v1", "resource": "pods" },
+        "namespace": "team-a",
+        "name": "test",
+        "operation": operation,
+        "userInfo": { "username": "admin", "groups": ["system:authenticated"] },
+        "dryRun": false
+    });
+    if let Some(object) = object {
+        request["object"] = object;
+    }
+    serde_json::from_value(request).unwrap()
+}
+
+fn pod_kind() -> Value {
+    json!({ "group": "", "version": "v1", "kind": "Pod" })
+}
+
+fn annotated_pod_object() -> Value {
+    annotated_pod(&[("syscallx86.com/container-port-injector", "true")])
+}
+
+#[test]
+fn test_admission_review_full_request() {
+    let review: AdmissionReviewRequest = serde_json::from_value(json!({
+        "apiVersion": "admission.k8s.io/v1",
+        "kind": "AdmissionReview",
+        "request": {
+            "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+            "kind": { "group": "", "version": "v1", "kind": "Pod" },
+            "resource": { "group": "", "version": "v1", "resource": "pods" },
+            "subResource": "ephemeralcontainers",
+            "requestKind": { "group": "", "version": "v1", "kind": "Pod" },
+            "namespace": "team-a",
+            "name": "test",
+            "operation": "UPDATE",
+            "userInfo": { "username": "admin", "uid": "014fbff9", "groups": ["system:masters"] },
+            "object": { "apiVersion": "v1", "kind": "Pod" },
+            "oldObject": { "apiVersion": "v1", "kind": "Pod" },
+            "dryRun": true,
+            "options": { "apiVersion": "meta.k8s.io/v1", "kind": "UpdateOptions" }
+        }
+    }))
+    .unwrap();
+
+    let request = review.request;
+    assert_eq!(Workload::from_kind(&request.kind, &[]), Some(Workload::Pod));
+    assert_eq!(request.operation, Operation::Update);
+    assert_eq!(request.user_info.username.as_deref(), Some("admin"));
+    assert!(request.old_object.is_some());
+    assert!(request.dry_run);
+}
+
+#[test]
+fn test_admission_review_v1beta1_is_echoed() {
+    let review = json!({
+        "apiVersion": "admission.k8s.io/v1beta1",
+        "kind": "AdmissionReview",
+        "request": {
+            "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+            "kind": { "group": "", "version": "v1", "kind": "Pod" },
+            "resource": { "group": "", "version": "v1", "resource": "pods" },
+            "operation": "CREATE"
+        }
+    });
+    let (review, version) = parse_review(review.to_string().as_bytes()).unwrap();
+    assert_eq!(version, AdmissionVersion::V1beta1);
+
+    let response = AdmissionResponse::empty(&review.request.uid).to_review(version);
+    assert_eq!(response.api_version, "admission.k8s.io/v1beta1");
+    assert_eq!(response.kind, "AdmissionReview");
+}
+
+#[test]
+fn test_admission_review_version_is_checked() {
+    let review = |api_version: &str, kind: &str| {
+        json!({ "apiVersion": api_version, "kind": kind, "request": {} }).to_string()
+    };
+
+    let error = parse_review(review("admission.k8s.io/v2", "AdmissionReview").as_bytes())
+        .err()
+        .unwrap();
+    assert!(error.contains("unsupported AdmissionReview apiVersion \"admission.k8s.io/v2\""));
+    assert!(
+        parse_review(review("admission.k8s.io/v1", "TokenReview").as_bytes())
+            .err()
+            .unwrap()
+            .contains("expected kind AdmissionReview")
+    );
+    assert!(
+        parse_review(b"{")
+            .err()
+            .unwrap()
+            .starts_with("malformed AdmissionReview")
+    );
+}
+
+#[tokio::test]
+async fn test_admit_create_patches_pod() {
+    let request = admission_request("CREATE", pod_kind(), Some(annotated_pod_object()));
+    let response = admit(&admission_state(), &request).await;
+
+    assert!(response.allowed);
+    assert!(response.patch.is_some());
+}
+
+#[tokio::test]
+async fn test_admit_update_leaves_pod_alone() {
+    let request = admission_request("UPDATE", pod_kind(), Some(annotated_pod_object()));
+    let response = admit(&admission_state(), &request).await;
+
+    assert!(response.allowed);
+    assert!(response.patch.is_none());
+}
+
+#[tokio::test]
+async fn test_admit_delete_without_object() {
+    let request = admission_request("DELETE", pod_kind(), None);
+    let response = admit(&admission_state(), &request).await;
+
+    assert!(response.allowed);
+    assert!(response.patch.is_none());
+}
+
+#[tokio::test]
+async fn test_admit_ignores_other_kinds() {
+    let kind = json!({ "group": "apps", "version": "v1", "kind": "Deployment" });
+    let object = json!({ "apiVersion": "apps/v1", "kind": "Deployment", "spec": {} });
+    let request = admission_request("CREATE", kind, Some(object));
+    let response = admit(&admission_state(), &request).await;
+
+    assert!(response.allowed);
+    assert!(response.patch.is_none());
+}
+
+#[tokio::test]
+async fn test_admit_skips_system_namespaces() {
+    let mut request = admission_request("CREATE", pod_kind(), Some(annotated_pod_object()));
+    request.namespace = Some("kube-system".to_string());
+    let response = admit(&admission_state(), &request).await;
+
+    assert!(response.allowed);
+    assert!(response.patch.is_none());
+}
+
+#[tokio::test]
+async fn test_validate_denies_missing_port() {
+    let request = admission_request("CREATE", pod_kind(), Some(annotated_pod_object()));
+    let response = validate_request(&admission_state(), &request).await;
+
+    assert!(!response.allowed);
+    assert!(response.patch.is_none());
+    let status = response.status.unwrap();
+    assert_eq!(status.code, 403);
+    assert_eq!(
+        status.message,
+        "Rule app: container app lacks port metrics (9200/TCP)"
+    );
+}
+
+#[tokio::test]
+async fn test_validate_denies_missing_container() {
+    let mut object = annotated_pod_object();
+    object["spec"]["containers"][0]["name"] = json!("worker");
+    let request = admission_request("CREATE", pod_kind(), Some(object));
+    let response = validate_request(&admission_state(), &request).await;
+
+    assert!(!response.allowed);
+    assert_eq!(
+        response.status.unwrap().message,
+        "Rule app: no container matches name=app"
+    );
+}
+
+#[tokio::test]
+async fn test_validate_allows_mutated_and_unannotated_pods() {
+    let mut object = annotated_pod_object();
+    object["spec"]["containers"][0]["ports"] =
+        json!([{ "name": "metrics", "containerPort": 9200, "protocol": "TCP" }]);
+    let request = admission_request("CREATE", pod_kind(), Some(object));
+    assert!(validate_request(&admission_state(), &request).await.allowed);
+
+    let request = admission_request(
+        "CREATE",
+        pod_kind(),
+        Some(pod(json!([{ "name": "worker" }]))),
+    );
+    assert!(validate_request(&admission_state(), &request).await.allowed);
+}
+
+#[tokio::test]
+async fn test_validate_allows_pod_mutated_after_collision() {
+    let rule = |collision, conflict| {
+        Rule::new("apps")
+            .with_container(ContainerSelector::name("app-*").unwrap())
+            .with_port(PortPatch::new("metrics", 9200))
+            .with_on_collision(collision)
+            .with_on_conflict(conflict)
+    };
+    let mut pod = pod(json!([{ "name": "app-a" }, { "name": "app-b" }]));
+    pod["metadata"]["annotations"] = json!({ "syscallx86.com/container-port-injector": "true" });
+
+    // app-b gets metrics-2:9201
+    let mutated = patch_of(&[rule(CollisionPolicy::Next, ConflictPolicy::Rename)], &pod).await;
+    let mut object = pod.clone();
+    jsonpatch::apply(&mut object, &mutated.ops).unwrap();
+
+    for collision in [
+        CollisionPolicy::Skip,
+        CollisionPolicy::Next,
+        CollisionPolicy::Fail,
+    ] {
+        for conflict in [
+            ConflictPolicy::Skip,
+            ConflictPolicy::Replace,
+            ConflictPolicy::Rename,
+            ConflictPolicy::Fail,
+        ] {
+            let state =
+                AppState::build(&Config::default().with_rules(vec![rule(collision, conflict)]));
+            let request = admission_request("CREATE", pod_kind(), Some(object.clone()));
+            let response = validate_request(&state, &request).await;
+
+            assert!(
+                response.allowed,
+                "{:?}/{:?}: {:?}",
+                collision,
+                conflict,
+                response.status.map(|s| s.message)
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_warnings_for_skipped_ports() {
+    let missing = pod(json!([{ "name": "worker" }]));
+    assert_eq!(
+        warnings(&[metrics_rule()], &missing).await,
+        vec!["Rule app: no container matches name=app"]
+    );
+
+    let existing = pod(json!([
+        { "name": "app", "ports": [{ "containerPort": 9200 }] }
+    ]));
+    assert_eq!(
+        warnings(&[metrics_rule()], &existing).await,
+        vec!["Rule app: port 9200 already exists in container app"]
+    );
+
+    // the same port under the same name is what the rule asks for
+    let same = pod(json!([
+        { "name": "app", "ports": [{ "name": "metrics", "containerPort": 9200 }] }
+    ]));
+    assert!(warnings(&[metrics_rule()], &same).await.is_empty());
+
+    let fresh = pod(json!([{ "name": "app" }]));
+    assert!(warnings(&[metrics_rule()], &fresh).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_admit_returns_warnings() {
+    let mut object = annotated_pod_object();
+    object["metadata"]["annotations"]["syscallx86.com/ports"] = json!("metrics:http");
+    object["spec"]["containers"][0]["ports"] = json!([{ "containerPort": 9200 }]);
+    let request = admission_request("CREATE", pod_kind(), Some(object));
+    let response = admit(&admission_state(), &request).await;
+
+    assert!(response.allowed);
+    assert!(response.patch.is_none());
+    assert_eq!(
+        response.warnings,
+        vec![
+            "syscallx86.com/ports: http is not a valid port number",
+            "Rule app: port 9200 already exists in container app"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_admit_warns_about_unparsable_object() {
+    let mut object = annotated_pod_object();
+    object["spec"] = json!("broken");
+    let request = admission_request("CREATE", pod_kind(), Some(object));
+    let response = admit(&admission_state(), &request).await;
+
+    assert!(response.allowed);
+    assert_eq!(response.warnings.len(), 1);
+    assert!(response.warnings[0].starts_with("port injector: failed to parse Pod"));
+}
+
+#[tokio::test]
+async fn test_deny_status_has_reason() {
+    let rule = metrics_rule().with_on_collision(CollisionPolicy::Fail);
+    let state = AppState::build(&Config::default().with_rules(vec![rule]));
+    let mut object = annotated_pod_object();
+    object["spec"]["containers"] = json!([
+        { "name": "app" },
+        { "name": "envoy", "ports": [{ "containerPort": 9200 }] }
+    ]);
+    let request = admission_request("CREATE", pod_kind(), Some(object));
+    let response = admit(&state, &request).await;
+
+    assert!(!response.allowed);
+    let status = response.status.unwrap();
+    assert_eq!(status.code, 409);
+    assert_eq!(status.reason, "Conflict");
+}
+
+#[test]
+fn test_deny_status_reasons() {
+    let reason = |code| {
+        AdmissionResponse::deny("uid", code, "")
+            .status
+            .unwrap()
+            .reason
+    };
+    assert_eq!(reason(StatusCode::INTERNAL_SERVER_ERROR), "InternalError");
+    assert_eq!(reason(StatusCode::FORBIDDEN), "Forbidden");
+    assert_eq!(reason(StatusCode::CONFLICT), "Conflict");
+}
+
+#[tokio::test]
+async fn test_audit_annotations_describe_patch() {
+    let request = admission_request("CREATE", pod_kind(), Some(annotated_pod_object()));
+    let response = admit(&admission_state(), &request).await;
+
+    assert_eq!(
+        response.audit_annotations,
+        BTreeMap::from([
+            ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+            ("rules".to_string(), "app".to_string()),
+            (
+                "injected-ports".to_string(),
+                "app:metrics=9200/TCP".to_string()
+            ),
+        ])
+    );
+}
+
+#[tokio::test]
+async fn test_audit_annotations_skip_reason() {
+    let request = admission_request("CREATE", pod_kind(), Some(pod(json!([{ "name": "app" }]))));
+    let response = admit(&admission_state(), &request).await;
+    assert_eq!(
+        response.audit_annotations["skip-reason"],
+        "not annotated for injection"
+    );
+
+    let request = admission_request("UPDATE", pod_kind(), Some(annotated_pod_object()));
+    let response = admit(&admission_state(), &request).await;
+    assert_eq!(
+        response.audit_annotations["skip-reason"],
+        "UPDATE of Pod is not patched"
+    );
+
+    let mut object = annotated_pod_object();
+    object["spec"]["containers"][0]["ports"] = json!([{ "containerPort": 9200 }]);
+    let request = admission_request("CREATE", pod_kind(), Some(object));
+    let response = admit(&admission_state(), &request).await;
+    assert_eq!(
+        response.audit_annotations["skip-reason"],
+        "nothing to inject"
+    );
+    assert_eq!(response.audit_annotations["rules"], "app");
+}
+
+#[tokio::test]
+async fn test_patched_pod_is_stamped() {
+    let pod = pod(json!([{ "name": "app" }]));
+    let patch = build(&[metrics_rule()], &pod).await.unwrap();
+
+    assert_eq!(
+        patch[1],
+        json!({
+            "op": "add",
+            "path": "/metadata/annotations",
+            "value": {
+                "syscallx86.com/injected-ports": "metrics:9200",
+                "syscallx86.com/status": "injected"
+            }
+        })
+    );
+}
+
+fn reinvoked_pod(containers: Value) -> Value {
+    let mut pod = pod(containers);
+    pod["metadata"]["annotations"] = json!({
+        "syscallx86.com/injected-ports": "metrics:9200",
+        "syscallx86.com/status": "injected"
+    });
+    pod
+}
+
+#[tokio::test]
+async fn test_reinvocation_recognizes_own_ports() {
+    let pod = reinvoked_pod(json!([
+        { "name": "app", "ports": [{ "name": "metrics", "containerPort": 9200, "protocol": "TCP" }] }
+    ]));
+    let patch = patch_of(&[metrics_rule()], &pod).await;
+
+    assert!(patch.ops.is_empty());
+    assert!(patch.warnings.is_empty());
+}
+
+#[tokio::test]
+async fn test_reinvocation_patches_sidecar_added_meanwhile() {
+    // envoy was injected by another webhook after our first invocation
+    let pod = reinvoked_pod(json!([
+        { "name": "app", "ports": [{ "name": "metrics", "containerPort": 9200, "protocol": "TCP" }] },
+        { "name": "envoy" }
+    ]));
+
+    let patch = build(&[metrics_rule(), envoy_rule()], &pod).await.unwrap();
+
+    assert_eq!(patch.len(), 2);
+    assert_eq!(patch[0]["path"], "/spec/containers/1/ports");
+    assert_eq!(
+        patch[1],
+        json!({
+            "op": "replace",
+            "path": "/metadata/annotations/syscallx86.com~1injected-ports",
+            "value": "envoy-metrics:20200,metrics:9200"
+        })
+    );
+}
+
+/// Patch of a second invocation on the pod the first one mutated.
+async fn reinvoke(rules: &[Rule], pod: &Value) -> Patch {
+    let first = patch_of(rules, pod).await;
+    let mut patched = pod.clone();
+    jsonpatch::apply(&mut patched, &first.ops).unwrap();
+
+    patch_of(rules, &patched).await
+}
+
+#[tokio::test]
+async fn test_reinvocation_after_renumber_and_rename() {
+    let pod = pod(json!([{ "name": "app-a" }, { "name": "app-b" }]));
+    let rules = [Rule::new("apps")
+        .with_container(ContainerSelector::name("app-*").unwrap())
+        .with_port(PortPatch::new("metrics", 9200))
+        .with_on_collision(CollisionPolicy::Next)
+        .with_on_conflict(ConflictPolicy::Rename)];
+
+    let patch = reinvoke(&rules, &pod).await;
+
+    assert!(patch.ops.is_empty());
+    assert!(patch.warnings.is_empty());
+}
+
+#[tokio::test]
+async fn test_reinvocation_after_renumber() {
+    let pod = pod(json!([
+        { "name": "app" },
+        { "name": "exporter", "ports": [{ "containerPort": 9200 }] }
+    ]));
+    let rules = [metrics_rule().with_on_collision(CollisionPolicy::Next)];
+
+    let patch = reinvoke(&rules, &pod).await;
+
+    assert!(patch.ops.is_empty());
+    assert!(patch.warnings.is_empty());
+}
+
+#[test]
+fn test_verify_patch() {
+    let object = annotated_pod_object();
+    let ops = |ops: Value| serde_json::from_value::<Vec<PatchOp>>(ops).unwrap();
+    let add_port = ops(json!([{
+        "op": "add",
+        "path": "/spec/containers/0/ports",
+        "value": [{ "name": "metrics", "containerPort": 9200 }]
+    }]));
+
+    let encoded = verify_patch(&Workload::Pod, &object, &add_port, 1024).unwrap();
+    assert_eq!(
+        serde_json::from_slice::<Value>(&encoded).unwrap()[0]["path"],
+        "/spec/containers/0/ports"
+    );
+
+    assert!(
+        verify_patch(&Workload::Pod, &object, &add_port, 16)
+            .unwrap_err()
+            .contains("exceeds the limit of 16 bytes")
+    );
+    assert!(
+        verify_patch(
+            &Workload::Pod,
+            &object,
+            &ops(json!([{ "op": "remove", "path": "/spec/volumes" }])),
+            1024
+        )
+        .unwrap_err()
+        .starts_with("patch does not apply")
+    );
+    // containerPort must be a number
+    assert!(
+        verify_patch(
+            &Workload::Pod,
+            &object,
+            &ops(json!([{
+                "op": "add",
+                "path": "/spec/containers/0/ports",
+                "value": [{ "containerPort": "metrics" }]
+            }])),
+            1024
+        )
+        .unwrap_err()
+        .starts_with("patched Pod is invalid")
+    );
+}
+
+#[tokio::test]
+async fn test_admit_patch_failure_policy() {
+    let request = admission_request("CREATE", pod_kind(), Some(annotated_pod_object()));
+    let state = |policy: PatchFailurePolicy| {
+        AppState::build(
+            &Config::default()
+                .with_rules(vec![metrics_rule()])
+                .with_patch(
+                    PatchConfig::default()
+                        .with_max_size(16)
+                        .with_failure_policy(policy),
+                ),
+        )
+    };
+
+    let allowed = admit(&state(PatchFailurePolicy::Allow), &request).await;
+    assert!(allowed.allowed);
+    assert!(allowed.patch.is_none());
+    assert_eq!(
+        allowed.audit_annotations["skip-reason"],
+        "patch failed verification"
+    );
+    assert!(allowed.warnings[0].contains("exceeds the limit of 16 bytes"));
+
+    let denied = admit(&state(PatchFailurePolicy::Deny), &request).await;
+    assert!(!denied.allowed);
+    assert_eq!(denied.status.unwrap().code, 500);
+}
+
+fn shipper_rule() -> Rule {
+    Rule::new("logs")
+        .with_container(ContainerSelector::name("app").unwrap())
+        .with_injection(
+            ContainerInjection::new(json!({
+                "name": "fluent-bit",
+                "image": "fluent/fluent-bit:3.1",
+                "volumeMounts": [{ "name": "app-logs", "mountPath": "/logs", "readOnly": true }]
+            }))
+            .with_volume(json!({ "name": "app-logs", "emptyDir": {} }))
+            .with_mount(json!({ "name": "app-logs", "mountPath": "/var/log/app" })),
+        )
+}
+
+#[tokio::test]
+async fn test_injects_sidecar_with_volumes() {
+    let pod = pod(json!([{ "name": "app" }]));
+
+    let patch = build(&[shipper_rule()], &pod).await.unwrap();
+
+    assert_eq!(
+        patch[..3],
+        [
+            json!({
+                "op": "add",
+                "path": "/spec/containers/0/volumeMounts",
+                "value": [{ "name": "app-logs", "mountPath": "/var/log/app" }]
+            }),
+            json!({
+                "op": "add",
+                "path": "/spec/containers/-",
+                "value": {
+                    "name": "fluent-bit",
+                    "image": "fluent/fluent-bit:3.1",
+                    "volumeMounts": [{ "name": "app-logs", "mountPath": "/logs", "readOnly": true }]
+                }
+            }),
+            json!({
+                "op": "add",
+                "path": "/spec/volumes",
+                "value": [{ "name": "app-logs", "emptyDir": {} }]
+            })
+        ]
+    );
+    assert_eq!(
+        patch[3]["value"]["syscallx86.com/injected-containers"],
+        "fluent-bit"
+    );
+}
+
+#[tokio::test]
+async fn test_injected_sidecar_gets_ports() {
+    let pod = pod(json!([{ "name": "app" }]));
+    let rule = Rule::new("exporter")
+        .with_container(ContainerSelector::name("node-exporter").unwrap())
+        .with_injection(ContainerInjection::new(
+            json!({ "name": "node-exporter", "image": "prom/node-exporter" }),
+        ))
+        .with_port(PortPatch::new("exporter", 9100));
+
+    let patch = build_ports(&[rule], &pod).await.unwrap();
+
+    assert_eq!(patch.len(), 1);
+    assert_eq!(patch[0]["path"], "/spec/containers/-");
+    assert_eq!(patch[0]["value"]["ports"][0]["containerPort"], 9100);
+}
+
+#[tokio::test]
+async fn test_native_sidecar_injection() {
+    let pod = pod(json!([{ "name": "app" }]));
+    let rule = Rule::new("proxy").with_injection(
+        ContainerInjection::new(json!({ "name": "proxy", "image": "envoyproxy/envoy" }))
+            .with_list(ContainerList::NativeSidecars),
+    );
+
+    let patch = build_ports(&[rule], &pod).await.unwrap();
+
+    assert_eq!(
+        patch,
+        vec![json!({
+            "op": "add",
+            "path": "/spec/initContainers",
+            "value": [{ "name": "proxy", "image": "envoyproxy/envoy", "restartPolicy": "Always" }]
+        })]
+    );
+}
+
+#[tokio::test]
+async fn test_existing_container_is_not_injected() {
+    let pod = pod_with_spec(json!({
+        "containers": [{ "name": "app" }, { "name": "fluent-bit", "image": "custom/shipper" }]
+    }));
+
+    let patch = patch_of(&[shipper_rule()], &pod).await;
+
+    // neither the volume nor its mount are added without the sidecar
+    assert!(patch.ops.is_empty());
+    assert_eq!(
+        patch.warnings,
+        vec!["Rule logs: container fluent-bit already exists, skipping"]
+    );
+}
+
+#[tokio::test]
+async fn test_reinvocation_recognizes_own_sidecar() {
+    let mut pod = pod_with_spec(json!({
+        "containers": [
+            { "name": "app", "volumeMounts": [{ "name": "app-logs", "mountPath": "/var/log/app" }] },
+            { "name": "fluent-bit" }
+        ],
+        "volumes": [{ "name": "app-logs", "emptyDir": {} }]
+    }));
+    pod["metadata"]["annotations"] = json!({
+        "syscallx86.com/injected-containers": "fluent-bit",
+        "syscallx86.com/status": "injected"
+    });
+
+    let patch = patch_of(&[shipper_rule()], &pod).await;
+
+    assert!(patch.ops.is_empty());
+    assert!(patch.warnings.is_empty());
+}
+
+#[tokio::test]
+async fn test_injection_keeps_pod_volumes_and_mounts() {
+    let pod = pod_with_spec(json!({
+        "containers": [
+            { "name": "app", "volumeMounts": [{ "name": "data", "mountPath": "/var/log/app" }] }
+        ],
+        "volumes": [{ "name": "app-logs", "hostPath": { "path": "/var/log" } }]
+    }));
+
+    let warnings = warnings(&[shipper_rule()], &pod).await;
+
+    assert_eq!(
+        warnings,
+        vec![
+            "Rule logs: volume app-logs already exists, keeping the one of the pod",
+            "Rule logs: /var/log/app is already mounted in container app, skipping"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_validate_denies_missing_sidecar() {
+    let state = AppState::build(&Config::default().with_rules(vec![shipper_rule()]));
+    let request = admission_request("CREATE", pod_kind(), Some(annotated_pod_object()));
+
+    let response = validate_request(&state, &request).await;
+
+    assert!(!response.allowed);
+    assert_eq!(
+        response.status.unwrap().message,
+        "Rule logs: container fluent-bit is missing"
+    );
+}
+
+fn metrics_env_rule() -> Rule {
+    Rule::new("metrics")
+        .with_container(ContainerSelector::name("app").unwrap())
+        .with_port(PortPatch::new("metrics", 9100).with_range(9100, 9199))
+        .with_env(EnvPatch::port("METRICS_PORT", "metrics"))
+        .with_env(EnvPatch::value_from(
+            "POD_NAME",
+            json!({ "fieldRef": { "fieldPath": "metadata.name" } }),
+        ))
+}
+
+#[tokio::test]
+async fn test_env_injection() {
+    let pod = pod(json!([{
+        "name": "app",
+        "ports": [{ "name": "http", "containerPort": 9100 }]
+    }]));
+
+    let patch = patch_of(&[metrics_env_rule()], &pod).await;
+
+    // 9100 is taken, so METRICS_PORT follows the allocated port
+    assert_eq!(
+        serde_json::to_value(&patch.ops[0]).unwrap(),
+        json!({
+            "op": "add",
+            "path": "/spec/containers/0/env",
+            "value": [
+                { "name": "METRICS_PORT", "value": "9101" },
+                { "name": "POD_NAME", "valueFrom": { "fieldRef": { "fieldPath": "metadata.name" } } }
+            ]
+        })
+    );
+    assert_eq!(patch.injected, vec!["app:metrics=9101/TCP"]);
+    assert_eq!(patch.env, vec!["app:METRICS_PORT", "app:POD_NAME"]);
+}
+
+#[tokio::test]
+async fn test_env_of_existing_port() {
+    let pod = pod(json!([{
+        "name": "app",
+        "ports": [{ "name": "metrics", "containerPort": 9150 }]
+    }]));
+
+    let patch = build_ports(&[metrics_env_rule()], &pod).await.unwrap();
+
+    assert_eq!(patch.len(), 1);
+    assert_eq!(
+        patch[0]["value"][0],
+        json!({ "name": "METRICS_PORT", "value": "9150" })
+    );
+}
+
+#[tokio::test]
+async fn test_env_without_port_is_left_out() {
+    let pod = pod(json!([{ "name": "app" }]));
+    let rules = [Rule::new("metrics")
+        .with_container(ContainerSelector::name("app").unwrap())
+        .with_env(EnvPatch::port("METRICS_PORT", "metrics"))];
+
+    assert!(build(&rules, &pod).await.unwrap().is_empty());
+    assert_eq!(
+        warnings(&rules, &pod).await,
+        vec!["Rule metrics: container app has no port metrics, env METRICS_PORT left out"]
+    );
+}
+
+#[tokio::test]
+async fn test_env_merge() {
+    let pod = pod(json!([{
+        "name": "app",
+        "env": [
+            { "name": "LOG_LEVEL", "value": "debug" },
+            { "name": "REGION", "value": "eu" },
+            { "name": "JAVA_OPTS", "value": "-Xmx1g" }
+        ]
+    }]));
+    let rules = [Rule::new("env")
+        .with_container(ContainerSelector::name("app").unwrap())
+        .with_env(EnvPatch::value("LOG_LEVEL", "info"))
+        .with_env(EnvPatch::value("REGION", "us").with_merge(EnvMerge::Override))
+        .with_env(
+            EnvPatch::value("JAVA_OPTS", "-javaagent:/agent.jar").with_merge(EnvMerge::Append),
+        )];
+
+    let patch = build_ports(&rules, &pod).await.unwrap();
+
+    assert_eq!(
+        patch,
+        vec![
+            json!({ "op": "replace", "path": "/spec/containers/0/env/1/value", "value": "us" }),
+            json!({
+                "op": "replace",
+                "path": "/spec/containers/0/env/2/value",
+                "value": "-Xmx1g -javaagent:/agent.jar"
+            }),
+        ]
+    );
+    assert_eq!(
+        warnings(&rules, &pod).await,
+        vec![
+            "Rule env: env LOG_LEVEL already set in container app, keeping it",
+            "Rule env: env REGION already set in container app, overriding it",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_env_append_is_idempotent() {
+    let pod = pod(json!([{
+        "name": "app",
+        "env": [{ "name": "JAVA_OPTS", "value": "-Xmx1g -javaagent:/agent.jar" }]
+    }]));
+    let rules = [Rule::new("env")
+        .with_container(ContainerSelector::name("app").unwrap())
+        .with_env(
+            EnvPatch::value("JAVA_OPTS", "-javaagent:/agent.jar").with_merge(EnvMerge::Append),
+        )];
+
+    assert!(build(&rules, &pod).await.unwrap().is_empty());
+    assert!(warnings(&rules, &pod).await.is_empty());
+}
+
+fn sourced_rule(source: PortSource) -> Rule {
+    Rule::new("metrics")
+        .with_container(ContainerSelector::name("app").unwrap())
+        .with_port(PortPatch::new("metrics", 0).with_source(source))
+}
+
+#[tokio::test]
+async fn test_port_from_container() {
+    let mut pod = pod(json!([{
+        "name": "app",
+        "command": ["/bin/app", "--metrics-addr=:9102"],
+        "args": ["--admin-addr", "0.0.0.0:9901"],
+        "env": [{ "name": "METRICS_PORT", "value": "9103" }]
+    }]));
+    pod["metadata"]["annotations"] = json!({ "prometheus.io/port": "9104" });
+
+    for (source, number) in [
+        (PortSource::Arg("--metrics-addr".to_string()), 9102),
+        (PortSource::Arg("--admin-addr".to_string()), 9901),
+        (PortSource::Env("METRICS_PORT".to_string()), 9103),
+        (
+            PortSource::Annotation("prometheus.io/port".to_string()),
+            9104,
+        ),
+    ] {
+        let patch = build_ports(&[sourced_rule(source)], &pod).await.unwrap();
+        assert_eq!(patch[0]["value"][0]["containerPort"], number);
+    }
+}
+
+#[tokio::test]
+async fn test_port_without_source_is_not_injected() {
+    let pod = pod(json!([{
+        "name": "app",
+        "args": ["--metrics-address=:9102"],
+        "env": [{ "name": "METRICS_PORT", "valueFrom": { "configMapKeyRef": { "name": "c", "key": "k" } } }]
+    }]));
+
+    for source in [
+        PortSource::Arg("--metrics-addr".to_string()),
+        PortSource::Env("METRICS_PORT".to_string()),
+        PortSource::Annotation("prometheus.io/port".to_string()),
+    ] {
+        let rules = [sourced_rule(source)];
+        assert!(build(&rules, &pod).await.unwrap().is_empty());
+        assert!(warnings(&rules, &pod).await.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn test_port_from_invalid_source() {
+    let pod = pod(json!([{
+        "name": "app",
+        "env": [{ "name": "METRICS_PORT", "value": "metrics" }]
+    }]));
+    let rules = [sourced_rule(PortSource::Env("METRICS_PORT".to_string()))];
+
+    assert!(build(&rules, &pod).await.unwrap().is_empty());
+    assert_eq!(
+        warnings(&rules, &pod).await,
+        vec!["Rule metrics: env METRICS_PORT of container app is not a port (metrics), skipping"]
+    );
+}
+
+fn monitored_rule(monitoring: Monitoring) -> Rule {
+    Rule::new("metrics")
+        .with_container(ContainerSelector::name("app").unwrap())
+        .with_port(PortPatch::new("metrics", 9100).with_range(9100, 9199))
+        .with_monitoring(monitoring)
+}
+
+async fn annotations_of(rules: &[Rule], pod: &Value) -> Value {
+    let patch = patch_of(rules, pod).await;
+    let mut patched = pod.clone();
+    jsonpatch::apply(&mut patched, &patch.ops).unwrap();
+    patched["metadata"]["annotations"].clone()
+}
+
+#[tokio::test]
+async fn test_prometheus_annotations() {
+    let pod = pod(json!([{
+        "name": "app",
+        "ports": [{ "name": "http", "containerPort": 9100 }]
+    }]));
+    let rules = [monitored_rule(Monitoring::new(
+        MonitoringPreset::Prometheus,
+    ))];
+
+    let annotations = annotations_of(&rules, &pod).await;
+
+    // the allocated port, not the first one of the range
+    assert_eq!(annotations["prometheus.io/scrape"], "true");
+    assert_eq!(annotations["prometheus.io/port"], "9101");
+    assert_eq!(annotations["prometheus.io/path"], "/metrics");
+
+    let patch = patch_of(&rules, &pod).await;
+    assert_eq!(
+        patch.annotations,
+        vec![
+            "prometheus.io/scrape",
+            "prometheus.io/port",
+            "prometheus.io/path"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_datadog_annotations() {
+    let pod = pod(json!([{ "name": "app" }]));
+    let rules = [monitored_rule(
+        Monitoring::new(MonitoringPreset::Datadog).with_path("/stats"),
+    )];
+
+    let annotations = annotations_of(&rules, &pod).await;
+    let checks: Value =
+        serde_json::from_str(annotations["ad.datadoghq.com/app.checks"].as_str().unwrap()).unwrap();
+
+    assert_eq!(
+        checks["openmetrics"]["instances"][0]["openmetrics_endpoint"],
+        "http://%%host%%:9100/stats"
+    );
+}
+
+#[tokio::test]
+async fn test_monitoring_respects_existing_annotations() {
+    let mut pod = pod(json!([{ "name": "app" }]));
+    pod["metadata"]["annotations"] = json!({ "prometheus.io/port": "8080" });
+
+    let kept = [monitored_rule(Monitoring::new(
+        MonitoringPreset::Prometheus,
+    ))];
+    assert_eq!(
+        annotations_of(&kept, &pod).await["prometheus.io/port"],
+        "8080"
+    );
+    assert_eq!(
+        warnings(&kept, &pod).await,
+        vec!["Rule metrics: annotation prometheus.io/port already set, keeping it"]
+    );
+
+    let overwritten = [monitored_rule(
+        Monitoring::new(MonitoringPreset::Prometheus).with_overwrite(true),
+    )];
+    assert_eq!(
+        annotations_of(&overwritten, &pod).await["prometheus.io/port"],
+        "9100"
+    );
+}
+
+#[tokio::test]
+async fn test_monitoring_reinvocation() {
+    let pod = pod(json!([{ "name": "app" }]));
+    let rules = [monitored_rule(Monitoring::new(
+        MonitoringPreset::Prometheus,
+    ))];
+
+    let patch = patch_of(&rules, &pod).await;
+    let mut patched = pod.clone();
+    jsonpatch::apply(&mut patched, &patch.ops).unwrap();
+
+    assert!(build(&rules, &patched).await.unwrap().is_empty());
+    assert!(warnings(&rules, &patched).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_monitoring_of_two_rules() {
+    let pod = pod(json!([{ "name": "app" }, { "name": "envoy" }]));
+    let rules = [
+        monitored_rule(Monitoring::new(MonitoringPreset::Prometheus)),
+        Rule::new("envoy")
+            .with_container(ContainerSelector::name("envoy").unwrap())
+            .with_port(PortPatch::new("envoy-metrics", 9200))
+            .with_monitoring(Monitoring::new(MonitoringPreset::Prometheus)),
+    ];
+
+    // the first rule wins, the second one keeps quiet about it
+    assert_eq!(
+        annotations_of(&rules, &pod).await["prometheus.io/port"],
+        "9100"
+    );
+    assert!(warnings(&rules, &pod).await.is_empty());
+    assert!(reinvoke(&rules, &pod).await.warnings.is_empty());
+}
+
+async fn build_ops(rules: &[Rule], pod: &Value) -> Vec<PatchOp> {
+    patch_of(rules, pod).await.ops
+}
+
+#[tokio::test]
+async fn test_consul_preset() {
+    let pod = pod_with_spec(json!({
+        "containers": [{ "name": "app", "image": "app:1.0" }],
+        "initContainers": [{
+            "name": "consul-dataplane",
+            "image": "hashicorp/consul-dataplane:1.6",
+            "restartPolicy": "Always"
+        }]
+    }));
+    let rules = [Rule::new("consul").with_preset(MeshPreset::Consul)];
+
+    let patch = build_ports(&rules, &pod).await.unwrap();
+    assert_eq!(patch.len(), 1);
+    assert_eq!(patch[0]["path"], "/spec/initContainers/0/ports");
+    assert_eq!(patch[0]["value"][1]["containerPort"], 20200);
+
+    let annotations = annotations_of(&rules, &pod).await;
+    assert_eq!(
+        annotations["consul.hashicorp.com/transparent-proxy-exclude-inbound-ports"],
+        "19000,20200"
+    );
+}
+
+#[tokio::test]
+async fn test_istio_preset_for_app_port() {
+    let mut pod = pod(json!([
+        { "name": "app", "image": "app:1.0" },
+        {
+            "name": "istio-proxy",
+            "image": "docker.io/istio/proxyv2:1.23.0",
+            "ports": [{ "name": "http-envoy-prom", "containerPort": 15090 }]
+        }
+    ]));
+    pod["metadata"]["annotations"] =
+        json!({ "traffic.sidecar.istio.io/excludeInboundPorts": "8081" });
+    let rules = [Rule::new("app-metrics")
+        .with_container(ContainerSelector::name("app").unwrap())
+        .with_port(PortPatch::new("metrics", 9102))
+        .with_preset(MeshPreset::Istio)];
+
+    let annotations = annotations_of(&rules, &pod).await;
+    assert_eq!(
+        annotations["traffic.sidecar.istio.io/excludeInboundPorts"],
+        "8081,9102"
+    );
+
+    // the sidecar ports of the preset, the prometheus one is there already
+    let sidecar = [Rule::new("istio").with_preset(MeshPreset::Istio)];
+    let patch = build_ports(&sidecar, &pod).await.unwrap();
+    assert_eq!(
+        patch,
+        vec![json!({
+            "op": "add",
+            "path": "/spec/containers/1/ports/-",
+            "value": { "name": "envoy-admin", "containerPort": 15000, "protocol": "TCP" }
+        })]
+    );
+    assert!(warnings(&sidecar, &pod).await.is_empty());
+    assert!(warnings(&rules, &pod).await.is_empty());
+
+    let mut patched = pod.clone();
+    jsonpatch::apply(&mut patched, &build_ops(&rules, &pod).await).unwrap();
+    assert!(build(&rules, &patched).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_istio_exclusion_before_sidecar_injection() {
+    // the Istio injector hasn't added istio-proxy yet
+    let pod = pod(json!([{ "name": "app", "image": "app:1.0" }]));
+    let rules = [Rule::new("istio").with_preset(MeshPreset::Istio)];
+
+    assert!(build_ports(&rules, &pod).await.unwrap().is_empty());
+    assert_eq!(
+        annotations_of(&rules, &pod).await["traffic.sidecar.istio.io/excludeInboundPorts"],
+        "15000,15090"
+    );
+    assert!(warnings(&rules, &pod).await.is_empty());
+    let reinvoked = reinvoke(&rules, &pod).await;
+    assert!(reinvoked.ops.is_empty());
+    assert!(reinvoked.warnings.is_empty());
+}
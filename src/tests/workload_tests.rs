@@ -0,0 +1,265 @@
+use crate::app::AppState;
+use crate::config::{AnnotationConfig, Config, InjectionMode, PortPatch, Rule};
+use crate::selector::{ContainerSelector, Pattern};
+use crate::webhook::{AdmissionRequest, admit};
+use crate::workload::{CustomResource, Workload};
+
+use base64::{Engine as _, engine::general_purpose};
+use serde_json::{Value, json};
+use std::sync::Arc;
+
+fn state(config: Config) -> AppState {
+    AppState::build(&config.with_rules(vec![
+        Rule::new("app")
+            .with_container(ContainerSelector::name("app").unwrap())
+            .with_port(PortPatch::new("metrics", 9200)),
+    ]))
+}
+
+fn template() -> Value {
+    json!({
+        "metadata": {
+            "labels": { "app": "web" },
+            "annotations": { "syscallx86.com/container-port-injector": "true" }
+        },
+        "spec": { "containers": [{ "name": "app" }] }
+    })
+}
+
+fn request(operation: &str, group: &str, kind: &str, object: Value) -> AdmissionRequest {
+    serde_json::from_value(json!({
+        "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+        "kind": { "group": group, "version": "v1", "kind": kind },
+        "resource": { "group": group, "version": "v1", "resource": kind.to_lowercase() },
+        "namespace": "team-a",
+        "name": "test",
+        "operation": operation,
+        "object": object
+    }))
+    .unwrap()
+}
+
+async fn patch(state: &AppState, request: &AdmissionRequest) -> Option<Vec<Value>> {
+    let response = admit(state, request).await;
+    assert!(response.allowed);
+
+    response.patch.map(|patch| {
+        let bytes = general_purpose::STANDARD.decode(patch).unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    })
+}
+
+#[test]
+fn test_workload_from_kind() {
+    let kind = |group: &str, kind: &str| {
+        serde_json::from_value(json!({ "group": group, "version": "v1", "kind": kind })).unwrap()
+    };
+
+    let rollout = CustomResource::pod_template("argoproj.io", "Rollout", "/spec/template")
+        .with_version("v1alpha1");
+    let custom = [rollout.clone()];
+
+    assert_eq!(
+        Workload::from_kind(&kind("apps", "StatefulSet"), &custom),
+        Some(Workload::StatefulSet)
+    );
+    assert_eq!(Workload::from_kind(&kind("", "Deployment"), &custom), None);
+    // owned by Deployments, which get the ports through their template
+    assert_eq!(
+        Workload::from_kind(&kind("apps", "ReplicaSet"), &custom),
+        None
+    );
+    assert_eq!(
+        Workload::from_kind(&kind("apps", "ControllerRevision"), &custom),
+        None
+    );
+    // v1 doesn't match the configured v1alpha1
+    assert_eq!(
+        Workload::from_kind(&kind("argoproj.io", "Rollout"), &custom),
+        None
+    );
+
+    let kind = serde_json::from_value(json!({
+        "group": "argoproj.io", "version": "v1alpha1", "kind": "Rollout"
+    }))
+    .unwrap();
+    assert_eq!(
+        Workload::from_kind(&kind, &custom),
+        Some(Workload::Custom(rollout))
+    );
+}
+
+#[tokio::test]
+async fn test_deployment_template_is_patched() {
+    let state = state(Config::default());
+    let object = json!({ "apiVersion": "apps/v1", "kind": "Deployment", "spec": { "template": template() } });
+
+    for operation in ["CREATE", "UPDATE"] {
+        let ops = patch(
+            &state,
+            &request(operation, "apps", "Deployment", object.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ops[0]["path"], "/spec/template/spec/containers/0/ports");
+    }
+}
+
+#[tokio::test]
+async fn test_cronjob_template_is_patched() {
+    let object = json!({
+        "apiVersion": "batch/v1",
+        "kind": "CronJob",
+        "spec": { "jobTemplate": { "spec": { "template": template() } } }
+    });
+    let ops = patch(
+        &state(Config::default()),
+        &request("CREATE", "batch", "CronJob", object),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        ops[0]["path"],
+        "/spec/jobTemplate/spec/template/spec/containers/0/ports"
+    );
+}
+
+#[tokio::test]
+async fn test_job_template_is_immutable() {
+    let object =
+        json!({ "apiVersion": "batch/v1", "kind": "Job", "spec": { "template": template() } });
+    let state = state(Config::default());
+
+    assert!(
+        patch(&state, &request("CREATE", "batch", "Job", object.clone()))
+            .await
+            .is_some()
+    );
+    assert!(
+        patch(&state, &request("UPDATE", "batch", "Job", object))
+            .await
+            .is_none()
+    );
+}
+
+#[tokio::test]
+async fn test_template_without_metadata_gets_allocation_annotation() {
+    let config = Config::default()
+        .with_annotations(AnnotationConfig::default().with_mode(InjectionMode::OptOut))
+        .with_rules(vec![
+            Rule::new("range")
+                .with_container(ContainerSelector::name("app").unwrap())
+                .with_port(PortPatch::new("metrics", 0).with_range(9100, 9199)),
+        ]);
+    let object = json!({
+        "apiVersion": "apps/v1",
+        "kind": "DaemonSet",
+        "spec": { "template": { "spec": { "containers": [{ "name": "app" }] } } }
+    });
+    let ops = patch(
+        &AppState::build(&config),
+        &request("CREATE", "apps", "DaemonSet", object),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(ops[0]["path"], "/spec/template/spec/containers/0/ports");
+    assert_eq!(
+        ops[1],
+        json!({
+            "op": "add",
+            "path": "/spec/template/metadata",
+            "value": { "annotations": {
+                "syscallx86.com/allocated-ports": "metrics=9100",
+                "syscallx86.com/injected-ports": "metrics:9100",
+                "syscallx86.com/status": "injected"
+            } }
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_custom_resource_pod_template() {
+    let config = Config::default().with_custom_resource(CustomResource::pod_template(
+        "serving.knative.dev",
+        "Service",
+        "/spec/template",
+    ));
+    // Knative containers don't need a name, the image selects them
+    let mut state = state(config);
+    state.rules = Arc::new(vec![
+        Rule::new("knative")
+            .with_container(
+                ContainerSelector::default().with_image(Pattern::parse("*/helloworld-go").unwrap()),
+            )
+            .with_port(PortPatch::new("metrics", 9200)),
+    ]);
+    let object = json!({
+        "apiVersion": "serving.knative.dev/v1",
+        "kind": "Service",
+        "spec": {
+            "template": {
+                "metadata": {
+                    "annotations": { "syscallx86.com/container-port-injector": "true" }
+                },
+                "spec": { "containers": [{ "image": "gcr.io/knative-samples/helloworld-go" }] }
+            }
+        }
+    });
+
+    let ops = patch(
+        &state,
+        &request("CREATE", "serving.knative.dev", "Service", object),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(ops[0]["path"], "/spec/template/spec/containers/0/ports");
+}
+
+#[tokio::test]
+async fn test_custom_resource_pod_spec_uses_resource_metadata() {
+    let config = Config::default().with_custom_resource(CustomResource::pod_spec(
+        "example.com",
+        "Worker",
+        "/spec/worker/podSpec",
+    ));
+    let object = json!({
+        "apiVersion": "example.com/v1",
+        "kind": "Worker",
+        "metadata": {
+            "name": "test",
+            "annotations": { "syscallx86.com/container-port-injector": "true" }
+        },
+        "spec": { "worker": { "podSpec": { "containers": [{ "name": "app" }] } } }
+    });
+
+    let ops = patch(
+        &state(config),
+        &request("UPDATE", "example.com", "Worker", object),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(ops[0]["path"], "/spec/worker/podSpec/containers/0/ports");
+}
+
+#[tokio::test]
+async fn test_custom_resource_without_pod_spec_is_allowed() {
+    let config = Config::default().with_custom_resource(CustomResource::pod_spec(
+        "example.com",
+        "Worker",
+        "/spec/podSpec",
+    ));
+    let object = json!({ "apiVersion": "example.com/v1", "kind": "Worker", "spec": {} });
+
+    assert!(
+        patch(
+            &state(config),
+            &request("CREATE", "example.com", "Worker", object)
+        )
+        .await
+        .is_none()
+    );
+}
@@ -1,11 +1,76 @@
 use base64::{Engine as _, engine::general_purpose};
-use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::api::authentication::v1::UserInfo;
+use k8s_openapi::api::core::v1::ContainerPort;
 //use kube::api::core::v1::Pod;
-use crate::{app::Container, prelude::*};
+use crate::{
+    config::{
+        AnnotationConfig, CollisionPolicy, ConflictPolicy, ContainerInjection, EnvMerge, EnvPatch,
+        EnvSource, InjectionMode, Monitoring, MonitoringPreset, PatchFailurePolicy, PortPatch,
+        PortSource, Protocol, Rule,
+    },
+    diff::diff_member,
+    jsonpatch::{self, PatchOp},
+    overrides::apply_overrides,
+    prelude::*,
+    selector::{ContainerList, label_selector_matches},
+    workload::Workload,
+};
 
 use poem::{Result, handler, http::StatusCode, web::Json};
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// Pod annotation listing ports allocated from a range, e.g. `metrics=9100`.
+pub const ALLOCATED_PORTS_ANNOTATION: &str = "allocated-ports";
+/// Pod annotation listing every port the webhook injected, e.g.
+/// `dns:53/UDP,metrics:9200`, so a reinvocation recognizes its own work.
+pub const INJECTED_PORTS_ANNOTATION: &str = "injected-ports";
+/// Pod annotation listing the containers added by injection rules.
+pub const INJECTED_CONTAINERS_ANNOTATION: &str = "injected-containers";
+/// Pod annotation set to `injected` once the webhook has patched the pod.
+pub const STATUS_ANNOTATION: &str = "status";
+pub const STATUS_INJECTED: &str = "injected";
+
+/// Keys of the audit annotations, the API server prefixes them with the
+/// webhook name.
+pub const AUDIT_VERSION: &str = "version";
+pub const AUDIT_RULES: &str = "rules";
+pub const AUDIT_INJECTED_PORTS: &str = "injected-ports";
+pub const AUDIT_INJECTED_CONTAINERS: &str = "injected-containers";
+pub const AUDIT_INJECTED_ENV: &str = "injected-env";
+pub const AUDIT_INJECTED_ANNOTATIONS: &str = "injected-annotations";
+pub const AUDIT_SKIP_REASON: &str = "skip-reason";
+
+/// Supported `admission.k8s.io` versions of the AdmissionReview. The
+/// response is sent in the version of the request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AdmissionVersion {
+    V1,
+    V1beta1,
+}
+
+impl AdmissionVersion {
+    pub fn parse(api_version: &str) -> Result<Self, String> {
+        match api_version {
+            "admission.k8s.io/v1" => Ok(AdmissionVersion::V1),
+            "admission.k8s.io/v1beta1" => Ok(AdmissionVersion::V1beta1),
+            _ => Err(format!(
+                "unsupported AdmissionReview apiVersion {:?}, expected admission.k8s.io/v1 or admission.k8s.io/v1beta1",
+                api_version
+            )),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdmissionVersion::V1 => "admission.k8s.io/v1",
+            AdmissionVersion::V1beta1 => "admission.k8s.io/v1beta1",
+        }
+    }
+}
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AdmissionReviewRequest {
@@ -18,9 +83,69 @@ pub struct AdmissionReviewRequest {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AdmissionRequest {
     pub uid: String,
-    #[serde(rename = "object")]
-    pub object: Pod,
-    // můžeš si sem přidat i další pole (operation, userInfo, atd.)
+    pub kind: GroupVersionKind,
+    pub resource: GroupVersionResource,
+    #[serde(rename = "subResource")]
+    pub sub_resource: Option<String>,
+    pub namespace: Option<String>,
+    pub name: Option<String>,
+    pub operation: Operation,
+    #[serde(rename = "userInfo", default)]
+    pub user_info: UserInfo,
+    /// Missing for DELETE.
+    pub object: Option<Value>,
+    /// Only set for UPDATE and DELETE.
+    #[serde(rename = "oldObject")]
+    pub old_object: Option<Value>,
+    #[serde(rename = "dryRun", default)]
+    pub dry_run: bool,
+    /// CreateOptions, UpdateOptions or DeleteOptions of the operation.
+    pub options: Option<Value>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct GroupVersionKind {
+    #[serde(default)]
+    pub group: String,
+    pub version: String,
+    pub kind: String,
+}
+
+impl fmt::Display for GroupVersionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.group.as_str() {
+            "" => write!(f, "{}/{}", self.version, self.kind),
+            group => write!(f, "{}/{}/{}", group, self.version, self.kind),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct GroupVersionResource {
+    #[serde(default)]
+    pub group: String,
+    pub version: String,
+    pub resource: String,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Operation {
+    Create,
+    Update,
+    Delete,
+    Connect,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Create => write!(f, "CREATE"),
+            Operation::Update => write!(f, "UPDATE"),
+            Operation::Delete => write!(f, "DELETE"),
+            Operation::Connect => write!(f, "CONNECT"),
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -32,7 +157,7 @@ pub struct AdmissionReviewResponse {
 }
 
 pub trait ToReview {
-    fn to_review(self) -> AdmissionReviewResponse;
+    fn to_review(self, version: AdmissionVersion) -> AdmissionReviewResponse;
 }
 
 #[derive(Serialize)]
@@ -43,7 +168,37 @@ pub struct AdmissionResponse {
     pub patch: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none", rename = "patchType")]
     pub patch_type: Option<String>,
-    // volitelné: status, warnings, auditAnnotations...
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+    /// Shown by `kubectl` as `Warning: ...`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// Recorded in the API server audit log of the request.
+    #[serde(
+        skip_serializing_if = "BTreeMap::is_empty",
+        rename = "auditAnnotations"
+    )]
+    pub audit_annotations: BTreeMap<String, String>,
+}
+
+/// Subset of `metav1.Status` returned with a denial.
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub code: u16,
+    /// Machine readable reason, e.g. `Conflict` or `Forbidden`.
+    pub reason: String,
+    pub message: String,
+}
+
+/// `metav1.StatusReason` for the codes the webhook denies with, unknown
+/// codes get the empty `StatusReasonUnknown`.
+fn status_reason(code: StatusCode) -> &'static str {
+    match code {
+        StatusCode::INTERNAL_SERVER_ERROR => "InternalError",
+        StatusCode::CONFLICT => "Conflict",
+        StatusCode::FORBIDDEN => "Forbidden",
+        _ => "",
+    }
 }
 
 impl AdmissionResponse {
@@ -53,119 +208,1656 @@ impl AdmissionResponse {
             allowed: true,
             patch: None,
             patch_type: None,
+            status: None,
+            warnings: Vec::new(),
+            audit_annotations: BTreeMap::from([(
+                AUDIT_VERSION.to_string(),
+                env!("CARGO_PKG_VERSION").to_string(),
+            )]),
         }
     }
 
-    pub fn with_patch(self, patch: &Vec<Value>) -> Self {
-        let b64 = match serde_json::to_vec(&patch) {
-            Ok(b) => general_purpose::STANDARD.encode(b),
-            Err(_) => return Self::empty(&self.uid),
-        };
+    pub fn deny(uid: &str, code: StatusCode, message: &str) -> Self {
+        AdmissionResponse {
+            allowed: false,
+            status: Some(Status {
+                code: code.as_u16(),
+                reason: status_reason(code).to_string(),
+                message: message.to_string(),
+            }),
+            ..Self::empty(uid)
+        }
+    }
+
+    pub fn with_warnings(mut self, warnings: Vec<String>) -> Self {
+        self.warnings.extend(warnings);
+        self
+    }
+
+    /// Empty values are left out.
+    pub fn with_audit_annotation(mut self, key: &str, value: &str) -> Self {
+        if !value.is_empty() {
+            self.audit_annotations
+                .insert(key.to_string(), value.to_string());
+        }
+        self
+    }
+
+    /// Allows the request unchanged and records why.
+    pub fn skipped(uid: &str, reason: &str) -> Self {
+        Self::empty(uid).with_audit_annotation(AUDIT_SKIP_REASON, reason)
+    }
 
+    /// Attaches an encoded JSON Patch, see `verify_patch`.
+    pub fn with_patch(self, patch: &[u8]) -> Self {
         AdmissionResponse {
-            uid: self.uid,
-            allowed: true,
-            patch: Some(b64),
+            patch: Some(general_purpose::STANDARD.encode(patch)),
             patch_type: Some("JSONPatch".to_string()),
+            ..self
         }
     }
 }
 
 impl ToReview for AdmissionResponse {
-    fn to_review(self) -> AdmissionReviewResponse {
+    fn to_review(self, version: AdmissionVersion) -> AdmissionReviewResponse {
         AdmissionReviewResponse {
-            api_version: "admission.k8s.io/v1".to_string(),
+            api_version: version.as_str().to_string(),
             kind: "AdmissionReview".to_string(),
             response: self,
         }
     }
 }
 
-pub async fn is_annotated(pod: &Pod, log: Arc<Logger>) -> bool {
-    match pod.metadata.annotations.as_ref() {
-        Some(annotations) => {
-            if let Some(value) = annotations.get("syscallx86.com/container-port-injector")
-                && value == "true"
-            {
-                log.info("Pod is annotated to inject to ports".to_string())
-                    .await;
-                return true;
-            }
+/// Decides whether the pod should be mutated, based on the injector
+/// annotation and the configured opt-in/opt-out mode.
+pub async fn is_annotated(pod: &Value, config: &AnnotationConfig, log: Arc<Logger>) -> bool {
+    let key = config.injector_key();
+    let value = pod["metadata"]["annotations"][key.as_str()].as_str();
 
+    match (config.mode, value) {
+        (InjectionMode::OptIn, Some(value)) if config.is_enabled(value) => {
+            log.info("Pod is annotated to inject to ports".to_string())
+                .await;
+            true
+        }
+        (InjectionMode::OptIn, Some(_)) => {
             log.info("Pod is annotated to skip injection.".to_string())
                 .await;
             false
         }
-        None => {
-            log.info("Pod has no annotation!".to_string()).await;
+        (InjectionMode::OptIn, None) => {
+            log.info(format!("Pod has no {} annotation!", key)).await;
+            false
+        }
+        (InjectionMode::OptOut, Some(value)) if config.is_disabled(value) => {
+            log.info("Pod is annotated to opt out of injection.".to_string())
+                .await;
             false
         }
+        (InjectionMode::OptOut, _) => {
+            log.info("Pod is not opted out, injecting ports".to_string())
+                .await;
+            true
+        }
     }
 }
 
-pub async fn build_patch(cp: &Container, pod: &Pod, log: Arc<Logger>) -> Option<Vec<Value>> {
-    log.info("Building patch...".to_string()).await;
+/// Which part of the pod a request is allowed to change.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PatchScope {
+    /// Regular pod admission, ephemeral containers are left alone.
+    Pod,
+    /// The `pods/ephemeralcontainers` subresource, only ephemeral containers
+    /// can change.
+    EphemeralContainers,
+}
+
+impl PatchScope {
+    /// Scope of a request, `None` when nothing may be patched. Container
+    /// ports are immutable once the pod exists, so a plain pod UPDATE is left
+    /// alone; only the ephemeral containers subresource can still change.
+    pub fn from_request(
+        workload: &Workload,
+        operation: Operation,
+        sub_resource: Option<&str>,
+    ) -> Option<Self> {
+        match (operation, sub_resource) {
+            (Operation::Create, None) => Some(PatchScope::Pod),
+            (Operation::Update, None) if workload.mutable_on_update() => Some(PatchScope::Pod),
+            (Operation::Update, Some("ephemeralcontainers")) if *workload == Workload::Pod => {
+                Some(PatchScope::EphemeralContainers)
+            }
+            _ => None,
+        }
+    }
+
+    fn allows(&self, list: ContainerList) -> bool {
+        (list == ContainerList::EphemeralContainers) == (*self == PatchScope::EphemeralContainers)
+    }
+}
+
+/// Working copy of the pod the rules are applied to, so every rule sees the
+/// ports added by the rules evaluated before it. The JSON Patch is the diff
+/// of the copy against the admitted pod.
+///
+/// The pod is kept as raw JSON (`metadata` and `spec`), since pod specs
+/// embedded in custom resources don't have to be valid `k8s_openapi` types,
+/// e.g. Knative allows containers without a name.
+struct PodPatch {
+    pod: Value,
+    /// Ports allocated from a range, by port name.
+    allocated: BTreeMap<String, u16>,
+    allocated_key: String,
+    /// Decisions the user should see in the `kubectl` output.
+    warnings: Vec<String>,
+    /// Ports injected in this run together with their container.
+    injected: Vec<(String, PortPatch)>,
+    /// Ports injected by earlier invocations, from the injected-ports
+    /// annotation, `name` → `number[/protocol]`.
+    previous: BTreeMap<String, String>,
+    injected_key: String,
+    /// Containers added by injection rules in this run.
+    injected_containers: Vec<String>,
+    /// Containers added by earlier invocations.
+    previous_containers: BTreeSet<String>,
+    containers_key: String,
+    /// Environment variables set in this run, as `container:name`.
+    injected_env: Vec<String>,
+    /// Monitoring and port exclusion annotations written in this run.
+    injected_annotations: Vec<String>,
+    status_key: String,
+}
+
+impl PodPatch {
+    fn new(pod: &Value, annotations: &AnnotationConfig) -> Self {
+        let injected_key = annotations.key(INJECTED_PORTS_ANNOTATION);
+        let previous = pod["metadata"]["annotations"][injected_key.as_str()]
+            .as_str()
+            .map(|v| {
+                v.split(',')
+                    .filter_map(|e| e.split_once(':'))
+                    .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let containers_key = annotations.key(INJECTED_CONTAINERS_ANNOTATION);
+        let previous_containers = pod["metadata"]["annotations"][containers_key.as_str()]
+            .as_str()
+            .map(|v| v.split(',').map(|name| name.trim().to_string()).collect())
+            .unwrap_or_default();
+
+        PodPatch {
+            pod: pod.clone(),
+            allocated: BTreeMap::new(),
+            allocated_key: annotations.key(ALLOCATED_PORTS_ANNOTATION),
+            warnings: Vec::new(),
+            injected: Vec::new(),
+            previous,
+            injected_key,
+            injected_containers: Vec::new(),
+            previous_containers,
+            containers_key,
+            injected_env: Vec::new(),
+            injected_annotations: Vec::new(),
+            status_key: annotations.key(STATUS_ANNOTATION),
+        }
+    }
+
+    /// Whether an earlier invocation injected this very port.
+    fn injected_before(&self, name: &str, number: u16, protocol: Protocol) -> bool {
+        self.previous.get(name) == Some(&injected_entry(number, protocol))
+    }
+
+    /// Whether `value` is the number of a port injected by an earlier
+    /// invocation.
+    fn injected_number_before(&self, value: &str) -> bool {
+        self.previous
+            .values()
+            .any(|entry| entry.split('/').next() == Some(value))
+    }
+
+    async fn warn(&mut self, log: &Logger, message: String) {
+        log.warn(message.clone()).await;
+        self.warnings.push(message);
+    }
+
+    /// Only logs what comes from an earlier invocation of the webhook, the
+    /// user is warned about everything else.
+    async fn report(&mut self, log: &Logger, own: bool, message: String) {
+        match own {
+            true => log.info(message).await,
+            false => self.warn(log, message).await,
+        }
+    }
+
+    fn containers(&self, list: ContainerList) -> impl Iterator<Item = &Value> {
+        items(&self.pod["spec"], list.field())
+    }
+
+    /// Indexes of the containers in `list` matched by the rule. Native
+    /// sidecars are reported as init containers, since that is where they live.
+    fn targets(&self, rule: &Rule, list: ContainerList) -> Vec<(ContainerList, usize)> {
+        let sidecars_only = list == ContainerList::NativeSidecars;
+        let list = match list {
+            ContainerList::NativeSidecars => ContainerList::InitContainers,
+            list => list,
+        };
+
+        self.containers(list)
+            .enumerate()
+            .filter(|(_, c)| !sidecars_only || str_field(c, "restartPolicy") == Some("Always"))
+            .filter(|(_, c)| {
+                rule.container
+                    .matches(container_name(c), str_field(c, "image"))
+            })
+            .map(|(idx, _)| (list, idx))
+            .collect()
+    }
+
+    /// Every port of the pod together with the container exposing it.
+    /// Containers and init containers share the pod network namespace.
+    fn pod_ports(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.containers(ContainerList::Containers)
+            .chain(self.containers(ContainerList::InitContainers))
+            .flat_map(|c| items(c, "ports").map(move |p| (container_name(c), p)))
+    }
+
+    fn container(&self, list: ContainerList, idx: usize) -> &Value {
+        &self.pod["spec"][list.field()][idx]
+    }
+
+    fn container_mut(&mut self, list: ContainerList, idx: usize) -> &mut Value {
+        &mut self.pod["spec"][list.field()][idx]
+    }
+
+    fn host_network(&self) -> bool {
+        self.pod["spec"]["hostNetwork"].as_bool() == Some(true)
+    }
 
-    let spec = pod.spec.as_ref()?;
+    fn add_port(&mut self, list: ContainerList, idx: usize, container_port: ContainerPort) {
+        let value = serde_json::to_value(&container_port).expect("container port");
 
-    // heuristika: name nebo image config.name -> zmenit
-    let idx = spec.containers.iter().position(|c| c.name == cp.name)?;
+        push_item(self.container_mut(list, idx), "ports", value);
+    }
+
+    /// Whether any container, init container or ephemeral container of the
+    /// pod has this name.
+    fn has_container(&self, name: &str) -> bool {
+        [
+            ContainerList::Containers,
+            ContainerList::InitContainers,
+            ContainerList::EphemeralContainers,
+        ]
+        .into_iter()
+        .any(|list| self.containers(list).any(|c| container_name(c) == name))
+    }
 
-    let container = &spec.containers[idx];
+    fn volume(&self, name: &str) -> Option<&Value> {
+        items(&self.pod["spec"], "volumes").find(|v| str_field(v, "name") == Some(name))
+    }
 
-    // když už port existuje, nic nepatchujeme
-    if let Some(ports) = &container.ports {
-        if ports
+    fn replace_port(
+        &mut self,
+        list: ContainerList,
+        idx: usize,
+        port_idx: usize,
+        container_port: ContainerPort,
+    ) {
+        let value = serde_json::to_value(&container_port).expect("container port");
+        self.container_mut(list, idx)["ports"][port_idx] = value;
+    }
+
+    fn set_annotation(&mut self, key: &str, value: &str) {
+        let metadata = &mut self.pod["metadata"];
+        if !metadata.is_object() {
+            // pod spec z custom resource nemusí mít metadata
+            *metadata = json!({});
+        }
+
+        let annotations = &mut metadata["annotations"];
+        match annotations.as_object_mut() {
+            Some(annotations) => {
+                annotations.insert(key.to_string(), json!(value));
+            }
+            None => *annotations = json!({ key: value }),
+        }
+    }
+
+    /// Merges the ports allocated in this run into the allocated-ports
+    /// annotation, keeping entries written by earlier invocations.
+    fn record_allocations(&mut self) {
+        if self.allocated.is_empty() {
+            return;
+        }
+
+        let mut entries: BTreeMap<String, String> = self.pod["metadata"]["annotations"]
+            [self.allocated_key.as_str()]
+        .as_str()
+        .map(|v| {
+            v.split(',')
+                .filter_map(|e| e.split_once('='))
+                .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+        for (name, number) in &self.allocated {
+            entries.insert(name.clone(), number.to_string());
+        }
+
+        let value = entries
             .iter()
-            .any(|p| p.container_port as u16 == cp.port_number)
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join(",");
+        let key = self.allocated_key.clone();
+        self.set_annotation(&key, &value);
+    }
+
+    /// Stamps the pod with the ports injected so far, including those of
+    /// earlier invocations, and with the status annotation.
+    fn record_injections(&mut self) {
+        if self.injected.is_empty()
+            && self.injected_containers.is_empty()
+            && self.injected_env.is_empty()
+            && self.injected_annotations.is_empty()
         {
+            return;
+        }
+
+        if !self.injected_containers.is_empty() {
+            let mut names = self.previous_containers.clone();
+            names.extend(self.injected_containers.iter().cloned());
+            let value = names.into_iter().collect::<Vec<_>>().join(",");
+            let key = self.containers_key.clone();
+            self.set_annotation(&key, &value);
+        }
+
+        if !self.injected.is_empty() {
+            self.record_ports();
+        }
+
+        let key = self.status_key.clone();
+        if self.pod["metadata"]["annotations"][key.as_str()].as_str() != Some(STATUS_INJECTED) {
+            self.set_annotation(&key, STATUS_INJECTED);
+        }
+    }
+
+    fn record_ports(&mut self) {
+        let mut entries = self.previous.clone();
+        for (_, port) in &self.injected {
+            entries.insert(
+                port.name.clone(),
+                injected_entry(port.number, port.protocol),
+            );
+        }
+
+        let value = entries
+            .iter()
+            .map(|(k, v)| format!("{}:{}", k, v))
+            .collect::<Vec<_>>()
+            .join(",");
+        let key = self.injected_key.clone();
+        self.set_annotation(&key, &value);
+    }
+}
+
+/// Appends to the array `field` of `parent`, creating it when missing.
+fn push_item(parent: &mut Value, field: &str, value: Value) {
+    match parent.get_mut(field).and_then(Value::as_array_mut) {
+        Some(items) => items.push(value),
+        None => parent[field] = json!([value]),
+    }
+}
+
+/// `number[/protocol]` entry of the injected-ports annotation, TCP is left
+/// out like in the per-pod ports override.
+fn injected_entry(number: u16, protocol: Protocol) -> String {
+    match protocol {
+        Protocol::Tcp => number.to_string(),
+        protocol => format!("{}/{}", number, protocol.as_str()),
+    }
+}
+
+/// Builds the ContainerPort for the patch. With `hostNetwork: true` the API
+/// server requires `hostPort` to be equal to `containerPort`.
+pub fn container_port(port: &PortPatch, host_network: bool) -> ContainerPort {
+    let host_port = if host_network {
+        Some(port.number)
+    } else {
+        port.host_port
+    };
+
+    ContainerPort {
+        name: Some(port.name.clone()),
+        container_port: i32::from(port.number),
+        protocol: Some(port.protocol.as_str().to_string()),
+        host_port: host_port.map(i32::from),
+        host_ip: port.host_ip.clone(),
+    }
+}
+
+fn str_field<'a>(v: &'a Value, key: &str) -> Option<&'a str> {
+    v.get(key).and_then(Value::as_str)
+}
+
+fn items<'a>(v: &'a Value, key: &str) -> impl Iterator<Item = &'a Value> {
+    v.get(key).and_then(Value::as_array).into_iter().flatten()
+}
+
+fn container_name(container: &Value) -> &str {
+    str_field(container, "name").unwrap_or_default()
+}
+
+/// String values of a JSON object such as `metadata.labels`.
+pub fn string_map(v: &Value) -> Option<BTreeMap<String, String>> {
+    v.as_object().map(|map| {
+        map.iter()
+            .filter_map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string())))
+            .collect()
+    })
+}
+
+pub fn selector_matches(rule: &Rule, pod: &Value) -> bool {
+    label_selector_matches(
+        &rule.selector,
+        string_map(&pod["metadata"]["labels"]).as_ref(),
+    )
+}
+
+fn port_name(p: &Value) -> Option<&str> {
+    str_field(p, "name")
+}
+
+fn port_number(p: &Value) -> Option<i64> {
+    p.get("containerPort").and_then(Value::as_i64)
+}
+
+fn same_protocol(p: &Value, protocol: Protocol) -> bool {
+    str_field(p, "protocol").unwrap_or("TCP") == protocol.as_str()
+}
+
+fn same_port(p: &Value, number: u16, protocol: Protocol) -> bool {
+    port_number(p) == Some(i64::from(number)) && same_protocol(p, protocol)
+}
+
+/// Port number already used by another container.
+fn number_collision(patch: &PodPatch, target: &str, port: &PortPatch) -> Option<String> {
+    patch
+        .pod_ports()
+        .find(|(container, p)| *container != target && same_port(p, port.number, port.protocol))
+        .map(|(container, _)| {
+            format!(
+                "port {}/{} is already used by container {}",
+                port.number,
+                port.protocol.as_str(),
+                container
+            )
+        })
+}
+
+/// Port name already used in the pod. Carries the index of the port when it
+/// belongs to the target container.
+struct NameConflict {
+    message: String,
+    target_idx: Option<usize>,
+}
+
+fn name_conflict(
+    patch: &PodPatch,
+    list: ContainerList,
+    idx: usize,
+    name: &str,
+) -> Option<NameConflict> {
+    let target = patch.container(list, idx);
+    if let Some(j) = items(target, "ports").position(|p| port_name(p) == Some(name)) {
+        return Some(NameConflict {
+            message: format!(
+                "port name {} is already used in container {}",
+                name,
+                container_name(target)
+            ),
+            target_idx: Some(j),
+        });
+    }
+
+    patch
+        .pod_ports()
+        .find(|(_, p)| port_name(p) == Some(name))
+        .map(|(container, _)| NameConflict {
+            message: format!(
+                "port name {} is already used by container {}",
+                name, container
+            ),
+            target_idx: None,
+        })
+}
+
+/// Port names are IANA_SVC_NAME: at most 15 characters, no leading, trailing
+/// or double hyphens.
+const PORT_NAME_MAX_LEN: usize = 15;
+
+/// `<name>-<n>`, shortened to fit the port name limit.
+fn renamed(name: &str, n: usize) -> Option<String> {
+    let suffix = format!("-{}", n);
+    let keep = PORT_NAME_MAX_LEN.checked_sub(suffix.len())?;
+    let base: String = name.chars().take(keep).collect();
+    Some(format!("{}{}", base.trim_end_matches('-'), suffix))
+}
+
+/// First `<name>-<n>` not used anywhere in the pod.
+fn rename_port(patch: &PodPatch, name: &str) -> Option<String> {
+    (2..100).find_map(|n| {
+        let candidate = renamed(name, n)?;
+        let used = patch
+            .pod_ports()
+            .any(|(_, p)| port_name(p) == Some(candidate.as_str()));
+        (!used).then_some(candidate)
+    })
+}
+
+/// Port of the container an earlier invocation injected for `port`, also
+/// when it was renumbered or renamed then, as name and number.
+fn injected_port(patch: &PodPatch, container: &Value, port: &PortPatch) -> Option<(String, u16)> {
+    items(container, "ports").find_map(|p| {
+        let name = port_name(p)?;
+        let number = u16::try_from(port_number(p)?).ok()?;
+        let same_name =
+            name == port.name || (2..100).any(|n| renamed(&port.name, n).as_deref() == Some(name));
+
+        (same_name
+            && same_protocol(p, port.protocol)
+            && patch.injected_before(name, number, port.protocol))
+        .then(|| (name.to_string(), number))
+    })
+}
+
+/// Lowest port number of `range` not used anywhere in the pod.
+fn free_port(patch: &PodPatch, range: RangeInclusive<u16>, protocol: Protocol) -> Option<u16> {
+    range
+        .into_iter()
+        .find(|n| !patch.pod_ports().any(|(_, p)| same_port(p, *n, protocol)))
+}
+
+/// Raw value of the port source, `None` when the container doesn't have it.
+/// Env vars set through `valueFrom` can't be read at admission.
+fn source_value(patch: &PodPatch, container: &Value, source: &PortSource) -> Option<String> {
+    match source {
+        PortSource::Env(name) => items(container, "env")
+            .find(|e| str_field(e, "name") == Some(name.as_str()))
+            .and_then(|e| str_field(e, "value"))
+            .map(str::to_string),
+        PortSource::Annotation(key) => patch.pod["metadata"]["annotations"][key.as_str()]
+            .as_str()
+            .map(str::to_string),
+        PortSource::Arg(flag) => {
+            let args: Vec<&str> = items(container, "command")
+                .chain(items(container, "args"))
+                .filter_map(Value::as_str)
+                .collect();
+            args.iter().enumerate().find_map(|(i, arg)| {
+                match arg.strip_prefix(flag.as_str())?.strip_prefix('=') {
+                    Some(value) => Some(value.to_string()),
+                    // --metrics-addr :9102
+                    None if *arg == flag => args.get(i + 1).map(|v| v.to_string()),
+                    None => None,
+                }
+            })
+        }
+    }
+}
+
+/// Port of values like `9102`, `:9102`, `0.0.0.0:9102` or `[::]:9102`.
+fn parse_port_value(value: &str) -> Option<u16> {
+    let port = value.rsplit_once(':').map_or(value, |(_, port)| port);
+    port.trim().parse().ok().filter(|n| *n > 0)
+}
+
+/// Injects one port. Returns the number the port has in the container, also
+/// when it was there already, and `None` when it was left out.
+async fn apply_port(
+    rule: &Rule,
+    patch: &mut PodPatch,
+    list: ContainerList,
+    idx: usize,
+    port: &PortPatch,
+    log: Arc<Logger>,
+) -> Result<Option<u16>, String> {
+    let container = patch.container(list, idx);
+    let target = container_name(container).to_string();
+    let mut port = port.clone();
+
+    if let Some(source) = &port.source {
+        let Some(value) = source_value(patch, container, source) else {
             log.info(format!(
-                "Port {} already exists in container {}",
-                cp.port_name, cp.port_number
+                "Rule {}: no {} in container {}, port {} not injected",
+                rule.name, source, target, port.name
             ))
             .await;
-            return None;
+            return Ok(None);
+        };
+        let Some(number) = parse_port_value(&value) else {
+            let msg = format!(
+                "Rule {}: {} of container {} is not a port ({}), skipping",
+                rule.name, source, target, value
+            );
+            patch.warn(&log, msg).await;
+            return Ok(None);
+        };
+        port.number = number;
+    }
+
+    if let Some((start, end)) = port.range {
+        // port z rozsahu už byl přidělen dřív
+        let allocated = items(container, "ports")
+            .filter(|p| port_name(p) == Some(port.name.as_str()))
+            .filter_map(port_number)
+            .find(|n| (i64::from(start)..=i64::from(end)).contains(n));
+        if let Some(number) = allocated {
+            let msg = format!(
+                "Rule {}: port {} already allocated as {} in container {}",
+                rule.name, port.name, number, target
+            );
+            let own = u16::try_from(number)
+                .is_ok_and(|n| patch.injected_before(&port.name, n, port.protocol));
+            patch.report(&log, own, msg).await;
+            return Ok(u16::try_from(number).ok());
         }
 
-        // ports existují → přidáme nový záznam na konec
-        let path = format!("/spec/containers/{}/ports/-", idx);
-        let op = json!({
-            "op": "add",
-            "path": path,
-            "value": {
-                "name": cp.port_name,
-                "containerPort": cp.port_number,
-                "protocol": "TCP"
+        match free_port(patch, start..=end, port.protocol) {
+            Some(number) => port.number = number,
+            None => {
+                let msg = format!("no free port left in range {}-{}", start, end);
+                if rule.on_collision == CollisionPolicy::Fail {
+                    return Err(format!("Rule {}: {}", rule.name, msg));
+                }
+                patch
+                    .warn(&log, format!("Rule {}: {}, skipping", rule.name, msg))
+                    .await;
+                return Ok(None);
             }
-        });
+        }
+    }
 
-        Some(vec![op])
-    } else {
-        // žádné ports → přidáme celé pole
-        let path = format!("/spec/containers/{}/ports", idx);
-        let op = json!({
-            "op": "add",
-            "path": path,
-            "value": [
-                {
-                    "name": cp.port_name,
-                    "containerPort": cp.port_number,
-                    "protocol": "TCP"
+    let container = patch.container(list, idx);
+
+    // když už port existuje, nic nepatchujeme; stejný port se stejným
+    // jménem je splněné pravidlo, ne kolize
+    let existing = items(container, "ports")
+        .find(|p| same_port(p, port.number, port.protocol))
+        .map(|p| port_name(p).unwrap_or_default().to_string());
+    if let Some(name) = existing {
+        let msg = format!(
+            "Rule {}: port {} already exists in container {}",
+            rule.name, port.number, target
+        );
+        let own = name == port.name || patch.injected_before(&name, port.number, port.protocol);
+        patch.report(&log, own, msg).await;
+        return Ok(Some(port.number));
+    }
+
+    // přečíslováno nebo přejmenováno už dřívějším voláním, kolize by se
+    // jinak řešila znovu
+    if let Some((name, number)) = injected_port(patch, container, &port) {
+        log.info(format!(
+            "Rule {}: port {} already injected as {}:{} in container {}",
+            rule.name, port.name, name, number, target
+        ))
+        .await;
+        return Ok(Some(number));
+    }
+
+    if let Some(msg) = number_collision(patch, &target, &port) {
+        match rule.on_collision {
+            CollisionPolicy::Fail => return Err(format!("Rule {}: {}", rule.name, msg)),
+            CollisionPolicy::Next => {
+                let next = port.number.saturating_add(1)..=u16::MAX;
+                let Some(number) = free_port(patch, next, port.protocol) else {
+                    return Err(format!("Rule {}: {}, no free port left", rule.name, msg));
+                };
+                patch
+                    .warn(
+                        &log,
+                        format!("Rule {}: {}, using port {} instead", rule.name, msg, number),
+                    )
+                    .await;
+                port.number = number;
+            }
+            CollisionPolicy::Skip => {
+                patch
+                    .warn(&log, format!("Rule {}: {}, skipping", rule.name, msg))
+                    .await;
+                return Ok(None);
+            }
+        }
+    }
+
+    let mut replace_idx = None;
+    if let Some(conflict) = name_conflict(patch, list, idx, &port.name) {
+        let msg = conflict.message;
+        match (rule.on_conflict, conflict.target_idx) {
+            (ConflictPolicy::Fail, _) => return Err(format!("Rule {}: {}", rule.name, msg)),
+            (ConflictPolicy::Replace, Some(j)) => {
+                patch
+                    .warn(&log, format!("Rule {}: {}, replacing it", rule.name, msg))
+                    .await;
+                replace_idx = Some(j);
+            }
+            (ConflictPolicy::Rename, _) => {
+                let Some(name) = rename_port(patch, &port.name) else {
+                    patch
+                        .warn(
+                            &log,
+                            format!("Rule {}: {}, no free name left, skipping", rule.name, msg),
+                        )
+                        .await;
+                    return Ok(None);
+                };
+                patch
+                    .warn(
+                        &log,
+                        format!("Rule {}: {}, using name {} instead", rule.name, msg, name),
+                    )
+                    .await;
+                port.name = name;
+            }
+            (ConflictPolicy::Replace, None) | (ConflictPolicy::Skip, _) => {
+                // port jiného kontejneru nepřepisujeme
+                patch
+                    .warn(&log, format!("Rule {}: {}, skipping", rule.name, msg))
+                    .await;
+                return Ok(None);
+            }
+        }
+    }
+
+    let host_network = patch.host_network();
+    if host_network && port.host_port.is_some_and(|hp| hp != port.number) {
+        patch
+            .warn(
+                &log,
+                format!(
+                    "Rule {}: pod uses host network, host port of {} set to {}",
+                    rule.name, port.name, port.number
+                ),
+            )
+            .await;
+    }
+
+    let container_port = container_port(&port, host_network);
+    match replace_idx {
+        Some(j) => patch.replace_port(list, idx, j, container_port),
+        None => patch.add_port(list, idx, container_port),
+    }
+    if port.range.is_some() {
+        patch.allocated.insert(port.name.clone(), port.number);
+    }
+    let number = port.number;
+    patch.injected.push((target, port));
+    Ok(Some(number))
+}
+
+/// The env var as it goes into the container, an error when the port it
+/// refers to is not there.
+fn env_var(
+    env: &EnvPatch,
+    container: &Value,
+    numbers: &BTreeMap<&str, u16>,
+) -> std::result::Result<Value, String> {
+    let value = match &env.source {
+        EnvSource::Value(value) => value.clone(),
+        EnvSource::ValueFrom(source) => {
+            return Ok(json!({ "name": env.name, "valueFrom": source }));
+        }
+        EnvSource::Port(name) => port_of(container, numbers, name)?.to_string(),
+    };
+    Ok(json!({ "name": env.name, "value": value }))
+}
+
+/// Number of the port `name` of the rule, as injected, or of the container.
+fn port_of(
+    container: &Value,
+    numbers: &BTreeMap<&str, u16>,
+    name: &str,
+) -> std::result::Result<u16, String> {
+    numbers
+        .get(name)
+        .copied()
+        .or_else(|| {
+            items(container, "ports")
+                .find(|p| port_name(p) == Some(name))
+                .and_then(port_number)
+                .and_then(|n| u16::try_from(n).ok())
+        })
+        .ok_or_else(|| {
+            format!(
+                "container {} has no port {}",
+                container_name(container),
+                name
+            )
+        })
+}
+
+/// Adds the port numbers to a comma separated annotation such as
+/// `traffic.sidecar.istio.io/excludeInboundPorts`, keeping its entries.
+fn exclude_ports(patch: &mut PodPatch, key: &str, numbers: impl Iterator<Item = u16>) {
+    let current = patch.pod["metadata"]["annotations"][key]
+        .as_str()
+        .unwrap_or_default();
+    let mut entries: Vec<String> = current
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    let listed = entries.len();
+    for number in numbers {
+        if !entries.contains(&number.to_string()) {
+            entries.push(number.to_string());
+        }
+    }
+    if entries.len() == listed {
+        return;
+    }
+
+    patch.set_annotation(key, &entries.join(","));
+    if !patch.injected_annotations.iter().any(|k| k == key) {
+        patch.injected_annotations.push(key.to_string());
+    }
+}
+
+/// Discovery annotations of the preset for a port of the container.
+fn monitoring_annotations(
+    rule: &Rule,
+    monitoring: &Monitoring,
+    container: &str,
+    number: u16,
+) -> Vec<(String, String)> {
+    match monitoring.preset {
+        MonitoringPreset::Prometheus => vec![
+            ("prometheus.io/scrape".to_string(), "true".to_string()),
+            ("prometheus.io/port".to_string(), number.to_string()),
+            ("prometheus.io/path".to_string(), monitoring.path.clone()),
+        ],
+        MonitoringPreset::Datadog => {
+            let checks = json!({
+                "openmetrics": {
+                    "instances": [{
+                        "openmetrics_endpoint": format!("http://%%host%%:{}{}", number, monitoring.path),
+                        "namespace": rule.name,
+                        "metrics": [".*"]
+                    }]
                 }
-            ]
-        });
+            });
+            vec![(
+                format!("ad.datadoghq.com/{}.checks", container),
+                checks.to_string(),
+            )]
+        }
+    }
+}
 
-        Some(vec![op])
+/// Writes the monitoring annotations for the port the rule injected into
+/// the container. Values the pod already has are kept unless the preset
+/// overwrites them; values the webhook wrote itself, in this run or with a
+/// port of an earlier invocation, are always kept.
+async fn apply_monitoring(
+    rule: &Rule,
+    patch: &mut PodPatch,
+    list: ContainerList,
+    idx: usize,
+    monitoring: &Monitoring,
+    numbers: &BTreeMap<&str, u16>,
+    log: Arc<Logger>,
+) {
+    let Some(name) = monitoring
+        .port
+        .as_deref()
+        .or(rule.ports.first().map(|p| p.name.as_str()))
+    else {
+        return;
+    };
+    let container = patch.container(list, idx);
+    let target = container_name(container).to_string();
+
+    let number = match port_of(container, numbers, name) {
+        Ok(number) => number,
+        Err(msg) => {
+            let msg = format!(
+                "Rule {}: {}, monitoring annotations left out",
+                rule.name, msg
+            );
+            patch.warn(&log, msg).await;
+            return;
+        }
+    };
+
+    for (key, value) in monitoring_annotations(rule, monitoring, &target, number) {
+        match patch.pod["metadata"]["annotations"][key.as_str()].as_str() {
+            Some(existing) if existing == value => continue,
+            Some(existing)
+                if patch.injected_annotations.contains(&key)
+                    || patch.injected_number_before(existing) =>
+            {
+                log.info(format!(
+                    "Rule {}: annotation {} already set by the webhook, keeping it",
+                    rule.name, key
+                ))
+                .await;
+                continue;
+            }
+            Some(_) if !monitoring.overwrite => {
+                let msg = format!(
+                    "Rule {}: annotation {} already set, keeping it",
+                    rule.name, key
+                );
+                patch.warn(&log, msg).await;
+                continue;
+            }
+            Some(_) => {
+                let msg = format!(
+                    "Rule {}: annotation {} already set, overwriting it",
+                    rule.name, key
+                );
+                patch.warn(&log, msg).await;
+            }
+            None => {}
+        }
+        patch.set_annotation(&key, &value);
+        patch.injected_annotations.push(key);
     }
 }
 
-#[handler]
-pub async fn mutate(state: Data<&AppState>, body: Body) -> Result<Json<AdmissionReviewResponse>> {
+/// Sets an env var in the container, an existing one is merged according to
+/// the rule. `numbers` are the ports the rule just injected, by their name
+/// in the rule.
+async fn apply_env(
+    rule: &Rule,
+    patch: &mut PodPatch,
+    list: ContainerList,
+    idx: usize,
+    env: &EnvPatch,
+    numbers: &BTreeMap<&str, u16>,
+    log: Arc<Logger>,
+) {
+    let container = patch.container(list, idx);
+    let target = container_name(container).to_string();
+
+    let var = match env_var(env, container, numbers) {
+        Ok(var) => var,
+        Err(msg) => {
+            let msg = format!("Rule {}: {}, env {} left out", rule.name, msg, env.name);
+            patch.warn(&log, msg).await;
+            return;
+        }
+    };
+
+    let existing =
+        items(container, "env").position(|e| str_field(e, "name") == Some(env.name.as_str()));
+    let Some(env_idx) = existing else {
+        push_item(patch.container_mut(list, idx), "env", var);
+        patch.injected_env.push(format!("{}:{}", target, env.name));
+        return;
+    };
+
+    let current = &patch.container(list, idx)["env"][env_idx];
+    if *current == var {
+        return;
+    }
+
+    let merged = match env.merge {
+        EnvMerge::Keep => {
+            let msg = format!(
+                "Rule {}: env {} already set in container {}, keeping it",
+                rule.name, env.name, target
+            );
+            patch.warn(&log, msg).await;
+            return;
+        }
+        EnvMerge::Override => {
+            let msg = format!(
+                "Rule {}: env {} already set in container {}, overriding it",
+                rule.name, env.name, target
+            );
+            patch.warn(&log, msg).await;
+            var
+        }
+        EnvMerge::Append => {
+            let (Some(old), Some(value)) = (str_field(current, "value"), str_field(&var, "value"))
+            else {
+                let msg = format!(
+                    "Rule {}: env {} of container {} is not a plain value, can't append to it",
+                    rule.name, env.name, target
+                );
+                patch.warn(&log, msg).await;
+                return;
+            };
+            // připojeno už dřívějším voláním
+            let appended = format!("{}{}", env.separator, value);
+            if old.ends_with(&appended) {
+                return;
+            }
+            json!({ "name": env.name, "value": format!("{}{}", old, appended) })
+        }
+    };
+
+    patch.container_mut(list, idx)["env"][env_idx] = merged;
+    patch.injected_env.push(format!("{}:{}", target, env.name));
+}
+
+/// Containers of all target lists allowed in the scope, each one only once.
+fn rule_targets(rule: &Rule, patch: &PodPatch, scope: PatchScope) -> Vec<(ContainerList, usize)> {
+    let mut targets: Vec<(ContainerList, usize)> = Vec::new();
+    for list in rule.targets.iter().filter(|l| scope.allows(**l)) {
+        for target in patch.targets(rule, *list) {
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+    }
+    targets
+}
+
+/// Adds the container of an injection rule together with its volumes.
+/// Returns whether the container is in the pod on behalf of the rule, i.e.
+/// added now or by an earlier invocation.
+async fn apply_injection(
+    rule: &Rule,
+    patch: &mut PodPatch,
+    injection: &ContainerInjection,
+    log: Arc<Logger>,
+) -> bool {
+    let name = injection.name();
+    if patch.has_container(name) {
+        let own = patch.previous_containers.contains(name);
+        let msg = format!(
+            "Rule {}: container {} already exists, skipping",
+            rule.name, name
+        );
+        patch.report(&log, own, msg).await;
+        return own;
+    }
+
+    for volume in &injection.volumes {
+        let volume_name = str_field(volume, "name").unwrap_or_default();
+        match patch.volume(volume_name) {
+            Some(existing) if existing == volume => {}
+            Some(_) => {
+                // svazek podu nepřepisujeme
+                let msg = format!(
+                    "Rule {}: volume {} already exists, keeping the one of the pod",
+                    rule.name, volume_name
+                );
+                patch.warn(&log, msg).await;
+            }
+            None => push_item(&mut patch.pod["spec"], "volumes", volume.clone()),
+        }
+    }
+
+    let mut container = injection.template.clone();
+    if injection.list == ContainerList::NativeSidecars && container["restartPolicy"].is_null() {
+        container["restartPolicy"] = json!("Always");
+    }
+    push_item(&mut patch.pod["spec"], injection.list.field(), container);
+    patch.injected_containers.push(name.to_string());
+
+    true
+}
+
+/// Adds a volume mount unless its path is already mounted in the container.
+async fn apply_mount(
+    rule: &Rule,
+    patch: &mut PodPatch,
+    list: ContainerList,
+    idx: usize,
+    mount: &Value,
+    log: Arc<Logger>,
+) {
+    let container = patch.container(list, idx);
+    let path = str_field(mount, "mountPath").unwrap_or_default();
+    let existing = items(container, "volumeMounts")
+        .find(|m| str_field(m, "mountPath") == Some(path))
+        .map(|m| str_field(m, "name") == str_field(mount, "name"));
+
+    match existing {
+        // připojeno už dřív, nejspíš námi
+        Some(true) => {}
+        Some(false) => {
+            let msg = format!(
+                "Rule {}: {} is already mounted in container {}, skipping",
+                rule.name,
+                path,
+                container_name(container)
+            );
+            patch.warn(&log, msg).await;
+        }
+        None => push_item(
+            patch.container_mut(list, idx),
+            "volumeMounts",
+            mount.clone(),
+        ),
+    }
+}
+
+async fn apply_rule(
+    rule: &Rule,
+    patch: &mut PodPatch,
+    scope: PatchScope,
+    log: Arc<Logger>,
+) -> Result<(), String> {
+    let mut mounts = Vec::new();
+    if scope == PatchScope::Pod {
+        for injection in &rule.inject {
+            if apply_injection(rule, patch, injection, log.clone()).await {
+                mounts.extend(injection.mounts.iter().cloned());
+            }
+        }
+    }
+
+    // pravidlo jen přidává kontejnery
+    if rule.container.is_empty() {
+        return Ok(());
+    }
+
+    let targets = rule_targets(rule, patch, scope);
+    if targets.is_empty() {
+        // sidecar mesh ještě nepřidala, ale svoje přesměrování staví z anotací
+        // podu, takže výjimka musí být v podu dřív než sidecar
+        if let (Some(key), PatchScope::Pod) = (&rule.exclude_annotation, scope) {
+            let fixed = rule
+                .ports
+                .iter()
+                .filter(|p| p.range.is_none() && p.source.is_none())
+                .map(|p| p.number);
+            exclude_ports(patch, key, fixed);
+            log.info(format!(
+                "Rule {}: no container matches {}, ports excluded ahead of the sidecar",
+                rule.name, rule.container
+            ))
+            .await;
+            return Ok(());
+        }
+        patch
+            .warn(
+                &log,
+                format!(
+                    "Rule {}: no container matches {}",
+                    rule.name, rule.container
+                ),
+            )
+            .await;
+        return Ok(());
+    }
+
+    for (list, idx) in targets {
+        if list == ContainerList::EphemeralContainers {
+            // API server zakazuje porty u ephemeral containers
+            if !rule.ports.is_empty() {
+                patch
+                    .warn(
+                        &log,
+                        format!(
+                            "Rule {}: ports are not allowed on ephemeral containers",
+                            rule.name
+                        ),
+                    )
+                    .await;
+            }
+            // env smí i ephemeral container, porty ale nemá
+            for env in &rule.env {
+                apply_env(rule, patch, list, idx, env, &BTreeMap::new(), log.clone()).await;
+            }
+            continue;
+        }
+
+        for mount in &mounts {
+            apply_mount(rule, patch, list, idx, mount, log.clone()).await;
+        }
+        let mut numbers = BTreeMap::new();
+        for port in &rule.ports {
+            if let Some(number) = apply_port(rule, patch, list, idx, port, log.clone()).await? {
+                numbers.insert(port.name.as_str(), number);
+            }
+        }
+        for env in &rule.env {
+            apply_env(rule, patch, list, idx, env, &numbers, log.clone()).await;
+        }
+        for monitoring in &rule.monitoring {
+            apply_monitoring(rule, patch, list, idx, monitoring, &numbers, log.clone()).await;
+        }
+        if let Some(key) = &rule.exclude_annotation {
+            exclude_ports(patch, key, numbers.values().copied());
+        }
+    }
+
+    Ok(())
+}
+
+/// JSON Patch built for a pod together with the warnings for the user and
+/// what ended up in the audit annotations.
+#[derive(Debug, Default)]
+pub struct Patch {
+    pub ops: Vec<PatchOp>,
+    pub warnings: Vec<String>,
+    /// Rules whose selector matched the pod.
+    pub rules: Vec<String>,
+    pub injected: Vec<String>,
+    pub containers: Vec<String>,
+    /// Environment variables set, as `container:name`.
+    pub env: Vec<String>,
+    /// Monitoring and port exclusion annotations written.
+    pub annotations: Vec<String>,
+}
+
+/// Applies all rules to a copy of the pod and diffs it against the original
+/// into one JSON Patch. An empty patch means there is nothing to change, an
+/// error means a rule asked for the pod to be rejected.
+pub async fn build_patch(
+    rules: &[Rule],
+    pod: &Value,
+    scope: PatchScope,
+    annotations: &AnnotationConfig,
+    log: Arc<Logger>,
+) -> Result<Patch, String> {
+    log.info("Building patch...".to_string()).await;
+
+    if !pod["spec"].is_object() {
+        return Ok(Patch::default());
+    }
+
+    let mut patch = PodPatch::new(pod, annotations);
+    let mut matched = Vec::new();
+
+    for rule in rules {
+        if !selector_matches(rule, pod) {
+            log.info(format!("Rule {}: pod labels do not match", rule.name))
+                .await;
+            continue;
+        }
+
+        matched.push(rule.name.clone());
+        apply_rule(rule, &mut patch, scope, log.clone()).await?;
+    }
+
+    // the ephemeralcontainers subresource ignores metadata, only spec is diffed
+    let members: &[&str] = match scope {
+        PatchScope::Pod => {
+            patch.record_allocations();
+            patch.record_injections();
+            // spec napřed, ať porty předchází anotace, které je zaznamenávají
+            &["spec", "metadata"]
+        }
+        PatchScope::EphemeralContainers => &["spec"],
+    };
+    let ops = members
+        .iter()
+        .flat_map(|key| diff_member(key, pod, &patch.pod))
+        .collect();
+
+    let injected = patch
+        .injected
+        .iter()
+        .map(|(container, port)| {
+            format!(
+                "{}:{}={}/{}",
+                container,
+                port.name,
+                port.number,
+                port.protocol.as_str()
+            )
+        })
+        .collect();
+
+    Ok(Patch {
+        ops,
+        warnings: patch.warnings,
+        rules: matched,
+        injected,
+        containers: patch.injected_containers,
+        env: patch.injected_env,
+        annotations: patch.injected_annotations,
+    })
+}
+
+/// Lists what the pod is missing according to the rules: containers the
+/// rules inject, target containers which don't exist and ports the mutation
+/// would still add. Rules asking for the pod to be rejected are reported as
+/// well.
+pub async fn missing_ports(
+    rules: &[Rule],
+    pod: &Value,
+    scope: PatchScope,
+    annotations: &AnnotationConfig,
+    log: Arc<Logger>,
+) -> Vec<String> {
+    let mut missing = Vec::new();
+    if !pod["spec"].is_object() {
+        return missing;
+    }
+
+    let mut patch = PodPatch::new(pod, annotations);
+
+    for rule in rules {
+        // ephemeral containers nemůžou mít porty
+        let has_targets = rule
+            .targets
+            .iter()
+            .any(|l| scope.allows(*l) && *l != ContainerList::EphemeralContainers);
+        if !selector_matches(rule, pod) {
+            continue;
+        }
+
+        let absent: Vec<&str> = rule
+            .inject
+            .iter()
+            .map(|i| i.name())
+            .filter(|name| scope == PatchScope::Pod && !patch.has_container(name))
+            .collect();
+        if !absent.is_empty() {
+            // bez vloženého kontejneru nemá smysl kontrolovat porty
+            for name in absent {
+                missing.push(format!("Rule {}: container {} is missing", rule.name, name));
+            }
+            continue;
+        }
+
+        if rule.ports.is_empty() || !has_targets {
+            continue;
+        }
+
+        if rule_targets(rule, &patch, scope).is_empty() {
+            missing.push(format!(
+                "Rule {}: no container matches {}",
+                rule.name, rule.container
+            ));
+            continue;
+        }
+
+        let applied = patch.injected.len();
+        if let Err(msg) = apply_rule(rule, &mut patch, scope, log.clone()).await {
+            missing.push(msg);
+            continue;
+        }
+
+        for (container, port) in &patch.injected[applied..] {
+            missing.push(format!(
+                "Rule {}: container {} lacks port {} ({}/{})",
+                rule.name,
+                container,
+                port.name,
+                port.number,
+                port.protocol.as_str()
+            ));
+        }
+    }
+
+    missing
+}
+
+/// Encodes the patch once it applies cleanly to a copy of the admitted
+/// object and the result is still a valid object, so a broken or oversized
+/// patch never reaches the API server.
+pub fn verify_patch(
+    workload: &Workload,
+    object: &Value,
+    ops: &[PatchOp],
+    max_size: usize,
+) -> std::result::Result<Vec<u8>, String> {
+    let encoded = serde_json::to_vec(ops).map_err(|e| format!("failed to encode patch: {}", e))?;
+    if encoded.len() > max_size {
+        return Err(format!(
+            "patch of {} bytes exceeds the limit of {} bytes",
+            encoded.len(),
+            max_size
+        ));
+    }
+
+    let mut patched = object.clone();
+    jsonpatch::apply(&mut patched, ops).map_err(|e| format!("patch does not apply: {}", e))?;
+    workload
+        .verify(&patched)
+        .map_err(|e| format!("patched {} is invalid: {}", workload, e))?;
+
+    Ok(encoded)
+}
+
+/// Request which passed the kind, operation, namespace and annotation
+/// checks, together with the rules after the per-pod overrides.
+struct Admitted<'a> {
+    workload: Workload,
+    object: &'a Value,
+    pod: Value,
+    scope: PatchScope,
+    rules: Vec<Rule>,
+    warnings: Vec<String>,
+}
+
+/// Runs the checks shared by mutation and validation. An error carries the
+/// response allowing the request unchanged.
+async fn prepare<'a>(
+    state: &AppState,
+    request: &'a AdmissionRequest,
+) -> std::result::Result<Admitted<'a>, AdmissionResponse> {
     let AppState {
         log,
-        container_properties,
+        rules,
+        annotations,
+        namespaces,
+        custom_resources,
         ..
-    } = *state;
+    } = state;
+    let uid = &request.uid;
+
+    let Some(workload) = Workload::from_kind(&request.kind, custom_resources) else {
+        log.info(format!(
+            "Ignoring {} of {}",
+            request.operation, request.kind
+        ))
+        .await;
+        return Err(AdmissionResponse::skipped(
+            uid,
+            &format!("{} is not handled", request.kind),
+        ));
+    };
+
+    let Some(scope) = PatchScope::from_request(
+        &workload,
+        request.operation,
+        request.sub_resource.as_deref(),
+    ) else {
+        log.info(format!(
+            "Nothing to patch on {} of {} {}",
+            request.operation,
+            workload,
+            request.name.as_deref().unwrap_or_default()
+        ))
+        .await;
+        return Err(AdmissionResponse::skipped(
+            uid,
+            &format!("{} of {} is not patched", request.operation, workload),
+        ));
+    };
+
+    let Some(object) = request.object.as_ref() else {
+        log.error("Request has no object".to_string()).await;
+        return Err(AdmissionResponse::skipped(uid, "request has no object")
+            .with_warnings(vec!["port injector: request has no object".to_string()]));
+    };
+    let pod =
+        match workload.pod(object) {
+            Ok(pod) => pod,
+            Err(e) => {
+                log.error(format!("Failed to parse {}: {}", workload, e))
+                    .await;
+                return Err(AdmissionResponse::skipped(uid, "object can't be parsed")
+                    .with_warnings(vec![format!(
+                        "port injector: failed to parse {}, no ports injected: {}",
+                        workload, e
+                    )]));
+            }
+        };
+
+    // pod z CREATE často nemá namespace v metadatech
+    let namespace = request
+        .namespace
+        .as_deref()
+        .or(pod["metadata"]["namespace"].as_str())
+        .unwrap_or("default");
+    if !namespaces.allows(namespace) {
+        log.info(format!("Namespace {} is excluded", namespace))
+            .await;
+        return Err(AdmissionResponse::skipped(
+            uid,
+            &format!("namespace {} is excluded", namespace),
+        ));
+    }
+
+    if !(is_annotated(&pod, annotations, log.clone()).await) {
+        return Err(AdmissionResponse::skipped(
+            uid,
+            "not annotated for injection",
+        ));
+    }
+
+    let pod_annotations = string_map(&pod["metadata"]["annotations"]);
+    let overrides = apply_overrides(rules, pod_annotations.as_ref(), annotations);
+    for warning in &overrides.warnings {
+        log.warn(format!("Invalid override: {}", warning)).await;
+    }
+
+    Ok(Admitted {
+        workload,
+        object,
+        pod,
+        scope,
+        rules: overrides.rules,
+        warnings: overrides.warnings,
+    })
+}
+
+/// Evaluates one admission request against the configured rules.
+pub async fn admit(state: &AppState, request: &AdmissionRequest) -> AdmissionResponse {
+    let uid = &request.uid;
+    let admitted = match prepare(state, request).await {
+        Ok(admitted) => admitted,
+        Err(response) => return response,
+    };
+    let Admitted {
+        workload,
+        object,
+        pod,
+        scope,
+        rules,
+        mut warnings,
+    } = admitted;
+    let log = &state.log;
+
+    match build_patch(&rules, &pod, scope, &state.annotations, log.clone()).await {
+        Ok(patch) if patch.ops.is_empty() => {
+            log.info("No patch needed".to_string()).await;
+            warnings.extend(patch.warnings);
+            let reason = match patch.rules.is_empty() {
+                true => "no rule matches the pod labels",
+                false => "nothing to inject",
+            };
+            AdmissionResponse::skipped(uid, reason)
+                .with_audit_annotation(AUDIT_RULES, &patch.rules.join(","))
+                .with_warnings(warnings)
+        }
+        Ok(patch) => {
+            warnings.extend(patch.warnings);
+            let ops = workload.prefix_patch(patch.ops);
+
+            match verify_patch(&workload, object, &ops, state.patch.max_size) {
+                Ok(encoded) => AdmissionResponse::empty(uid)
+                    .with_audit_annotation(AUDIT_RULES, &patch.rules.join(","))
+                    .with_audit_annotation(AUDIT_INJECTED_PORTS, &patch.injected.join(","))
+                    .with_audit_annotation(AUDIT_INJECTED_CONTAINERS, &patch.containers.join(","))
+                    .with_audit_annotation(AUDIT_INJECTED_ENV, &patch.env.join(","))
+                    .with_audit_annotation(AUDIT_INJECTED_ANNOTATIONS, &patch.annotations.join(","))
+                    .with_warnings(warnings)
+                    .with_patch(&encoded),
+                Err(msg) => {
+                    log.error(format!("Patch rejected: {}", msg)).await;
+                    let response = match state.patch.failure_policy {
+                        PatchFailurePolicy::Allow => {
+                            AdmissionResponse::skipped(uid, "patch failed verification")
+                                .with_warnings(warnings)
+                                .with_warnings(vec![format!(
+                                    "port injector: {}, no ports injected",
+                                    msg
+                                )])
+                        }
+                        PatchFailurePolicy::Deny => AdmissionResponse::deny(
+                            uid,
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            &format!("port injector: {}", msg),
+                        )
+                        .with_warnings(warnings),
+                    };
+                    response.with_audit_annotation(AUDIT_RULES, &patch.rules.join(","))
+                }
+            }
+        }
+        Err(msg) => {
+            log.warn(format!("Pod rejected: {}", msg)).await;
+            AdmissionResponse::deny(uid, StatusCode::CONFLICT, &msg).with_warnings(warnings)
+        }
+    }
+}
+
+/// Checks that an annotated pod already has everything the rules inject,
+/// so pods the mutating webhook missed are rejected.
+pub async fn validate_request(state: &AppState, request: &AdmissionRequest) -> AdmissionResponse {
+    let uid = &request.uid;
+    let admitted = match prepare(state, request).await {
+        Ok(admitted) => admitted,
+        Err(response) => return response,
+    };
+    let log = &state.log;
+
+    let missing = missing_ports(
+        &admitted.rules,
+        &admitted.pod,
+        admitted.scope,
+        &state.annotations,
+        log.clone(),
+    )
+    .await;
+    if missing.is_empty() {
+        return AdmissionResponse::empty(uid).with_warnings(admitted.warnings);
+    }
+
+    let msg = missing.join("; ");
+    log.warn(format!("{} rejected: {}", admitted.workload, msg))
+        .await;
+    AdmissionResponse::deny(uid, StatusCode::FORBIDDEN, &msg).with_warnings(admitted.warnings)
+}
+
+/// Parses an AdmissionReview and checks its kind and apiVersion.
+pub fn parse_review(data: &[u8]) -> Result<(AdmissionReviewRequest, AdmissionVersion), String> {
+    // verzi kontrolujeme dřív než zbytek, ať je chyba srozumitelná
+    let header: Value =
+        serde_json::from_slice(data).map_err(|e| format!("malformed AdmissionReview: {}", e))?;
+    if header["kind"] != "AdmissionReview" {
+        return Err(format!(
+            "expected kind AdmissionReview, got {}",
+            header["kind"]
+        ));
+    }
+    let version = AdmissionVersion::parse(header["apiVersion"].as_str().unwrap_or_default())?;
+
+    let review =
+        serde_json::from_value(header).map_err(|e| format!("malformed AdmissionReview: {}", e))?;
+    Ok((review, version))
+}
+
+async fn read_review(
+    state: &AppState,
+    body: Body,
+) -> Result<(AdmissionReviewRequest, AdmissionVersion)> {
+    let log = &state.log;
 
     let data = match body.into_bytes().await {
         Ok(data) => data,
@@ -175,32 +1867,30 @@ pub async fn mutate(state: Data<&AppState>, body: Body) -> Result<Json<Admission
         }
     };
 
-    let review: AdmissionReviewRequest = match serde_json::from_slice(&data) {
-        Ok(review) => review,
-        Err(_) => {
-            log.error("Failed to parse AdmissionReviewRequest".to_string())
+    match parse_review(&data) {
+        Ok(review) => Ok(review),
+        Err(e) => {
+            log.error(format!("Failed to parse AdmissionReviewRequest: {}", e))
                 .await;
-            return Err(StatusCode::BAD_REQUEST.into());
+            Err(Error::from_string(e, StatusCode::BAD_REQUEST))
         }
-    };
-
-    let uid = &review.request.uid;
-    let pod = &review.request.object;
-
-    if !(is_annotated(pod, log.clone()).await) {
-        return Ok(Json(AdmissionResponse::empty(uid).to_review()));
     }
+}
 
-    let patch_ops = build_patch(container_properties, pod, log.clone()).await;
-    let patch = match patch_ops {
-        Some(ref ops) => ops,
-        None => {
-            log.info("No patch needed".to_string()).await;
-            return Ok(Json(AdmissionResponse::empty(uid).to_review()));
-        }
-    };
-
-    let response = AdmissionResponse::empty(uid).with_patch(patch).to_review();
+#[handler]
+pub async fn mutate(state: Data<&AppState>, body: Body) -> Result<Json<AdmissionReviewResponse>> {
+    let (review, version) = read_review(&state, body).await?;
+    Ok(Json(
+        admit(&state, &review.request).await.to_review(version),
+    ))
+}
 
-    Ok(Json(response))
+#[handler]
+pub async fn validate(state: Data<&AppState>, body: Body) -> Result<Json<AdmissionReviewResponse>> {
+    let (review, version) = read_review(&state, body).await?;
+    Ok(Json(
+        validate_request(&state, &review.request)
+            .await
+            .to_review(version),
+    ))
 }
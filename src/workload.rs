@@ -0,0 +1,197 @@
+use crate::jsonpatch::{PatchOp, Pointer};
+use crate::webhook::GroupVersionKind;
+use k8s_openapi::api::apps::v1::{DaemonSet, Deployment, StatefulSet};
+use k8s_openapi::api::batch::v1::{CronJob, Job};
+use k8s_openapi::api::core::v1::Pod;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use std::fmt;
+
+/// Custom resource embedding a PodSpec or PodTemplateSpec, e.g. an Argo
+/// Rollout or a Knative Service.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CustomResource {
+    pub group: String,
+    /// Any version when not set.
+    pub version: Option<String>,
+    pub kind: String,
+    /// JSON Pointer of the embedded pod spec or template.
+    pub pointer: String,
+    /// Whether `pointer` refers to a PodTemplateSpec (`metadata` and `spec`)
+    /// rather than a bare PodSpec.
+    pub template: bool,
+}
+
+impl CustomResource {
+    pub fn pod_spec(group: &str, kind: &str, pointer: &str) -> Self {
+        CustomResource {
+            group: group.to_string(),
+            version: None,
+            kind: kind.to_string(),
+            pointer: pointer.to_string(),
+            template: false,
+        }
+    }
+
+    pub fn pod_template(group: &str, kind: &str, pointer: &str) -> Self {
+        CustomResource {
+            template: true,
+            ..Self::pod_spec(group, kind, pointer)
+        }
+    }
+
+    pub fn with_version(mut self, version: &str) -> Self {
+        self.version = Some(version.to_string());
+        self
+    }
+
+    fn pointer(&self) -> Pointer {
+        Pointer::parse(&self.pointer).expect("custom resource pointer")
+    }
+
+    pub fn matches(&self, kind: &GroupVersionKind) -> bool {
+        self.group == kind.group
+            && self.kind == kind.kind
+            && self.version.as_ref().is_none_or(|v| *v == kind.version)
+    }
+}
+
+/// Kind of object carrying the pod spec the rules are applied to.
+///
+/// ReplicaSets are left out on purpose: the Deployment controller finds its
+/// ReplicaSet by comparing pod templates, so a ReplicaSet patched without
+/// its Deployment would make it create new ReplicaSets over and over.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Workload {
+    Pod,
+    Deployment,
+    StatefulSet,
+    DaemonSet,
+    Job,
+    CronJob,
+    Custom(CustomResource),
+}
+
+impl Workload {
+    pub fn from_kind(kind: &GroupVersionKind, custom: &[CustomResource]) -> Option<Self> {
+        let workload = match (kind.group.as_str(), kind.kind.as_str()) {
+            ("", "Pod") => Workload::Pod,
+            ("apps", "Deployment") => Workload::Deployment,
+            ("apps", "StatefulSet") => Workload::StatefulSet,
+            ("apps", "DaemonSet") => Workload::DaemonSet,
+            ("batch", "Job") => Workload::Job,
+            ("batch", "CronJob") => Workload::CronJob,
+            _ => {
+                return custom
+                    .iter()
+                    .find(|c| c.matches(kind))
+                    .map(|c| Workload::Custom(c.clone()));
+            }
+        };
+
+        Some(workload)
+    }
+
+    /// JSON Pointer of the pod template, empty for a Pod itself.
+    fn template_pointer(&self) -> Pointer {
+        match self {
+            Workload::Pod => Pointer::root(),
+            Workload::CronJob => Pointer::root()
+                .key("spec")
+                .key("jobTemplate")
+                .key("spec")
+                .key("template"),
+            Workload::Custom(c) if c.template => c.pointer(),
+            // bare PodSpec, metadata of the resource itself
+            Workload::Custom(_) => Pointer::root(),
+            _ => Pointer::root().key("spec").key("template"),
+        }
+    }
+
+    pub fn metadata_pointer(&self) -> Pointer {
+        self.template_pointer().key("metadata")
+    }
+
+    pub fn spec_pointer(&self) -> Pointer {
+        match self {
+            Workload::Custom(c) if !c.template => c.pointer(),
+            _ => self.template_pointer().key("spec"),
+        }
+    }
+
+    /// Pod containers can't change once the pod exists and the template of a
+    /// Job is immutable, the other templates can be patched on UPDATE too.
+    pub fn mutable_on_update(&self) -> bool {
+        !matches!(self, Workload::Pod | Workload::Job)
+    }
+
+    /// The admitted pod, or the pod spec of a workload together with its
+    /// metadata, as `{"metadata": .., "spec": ..}`.
+    pub fn pod(&self, object: &Value) -> Result<Value, String> {
+        let spec = object
+            .pointer(self.spec_pointer().as_str())
+            .filter(|spec| spec.is_object())
+            .ok_or_else(|| format!("{} has no pod spec at {}", self, self.spec_pointer()))?;
+
+        let mut pod = json!({ "spec": spec });
+        if let Some(metadata) = object.pointer(self.metadata_pointer().as_str()) {
+            pod["metadata"] = metadata.clone();
+        }
+
+        Ok(pod)
+    }
+
+    /// Moves ops built against the pod to where its metadata and spec live.
+    pub fn prefix_patch(&self, ops: Vec<PatchOp>) -> Vec<PatchOp> {
+        if *self == Workload::Pod {
+            return ops;
+        }
+
+        let metadata = Pointer::root().key("metadata");
+        let spec = Pointer::root().key("spec");
+        let remap = |path: Pointer| {
+            if let Some(rest) = path.strip_prefix(&metadata) {
+                rest.under(&self.metadata_pointer())
+            } else if let Some(rest) = path.strip_prefix(&spec) {
+                rest.under(&self.spec_pointer())
+            } else {
+                path
+            }
+        };
+        ops.into_iter().map(|op| op.map_pointers(remap)).collect()
+    }
+
+    /// Checks that the patched object is still a valid workload. Custom
+    /// resources have no schema here, only their pod spec is looked up.
+    pub fn verify(&self, object: &Value) -> Result<(), String> {
+        fn parse<T: DeserializeOwned>(object: &Value) -> Result<(), String> {
+            serde_json::from_value::<T>(object.clone())
+                .map(drop)
+                .map_err(|e| e.to_string())
+        }
+
+        match self {
+            Workload::Pod => parse::<Pod>(object),
+            Workload::Deployment => parse::<Deployment>(object),
+            Workload::StatefulSet => parse::<StatefulSet>(object),
+            Workload::DaemonSet => parse::<DaemonSet>(object),
+            Workload::Job => parse::<Job>(object),
+            Workload::CronJob => parse::<CronJob>(object),
+            Workload::Custom(_) => self.pod(object).map(drop),
+        }
+    }
+}
+
+impl fmt::Display for Workload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Workload::Pod => write!(f, "Pod"),
+            Workload::Deployment => write!(f, "Deployment"),
+            Workload::StatefulSet => write!(f, "StatefulSet"),
+            Workload::DaemonSet => write!(f, "DaemonSet"),
+            Workload::Job => write!(f, "Job"),
+            Workload::CronJob => write!(f, "CronJob"),
+            Workload::Custom(c) => write!(f, "{}", c.kind),
+        }
+    }
+}